@@ -1,45 +1,662 @@
-///test cli
+/// command line front end: obx <subcommand>, obx help for the list.
+/// passwords are read from the terminal with echo off, or from the
+/// source --password-from names, never from argv
+extern crate clap;
+extern crate rayon;
+extern crate rpassword;
 extern crate salt_map;
 
-use salt_map::crypt::Crypt;
-use std::env;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use rayon::prelude::*;
+use salt_map::cipher::{KdfParams, Keyfile};
+use salt_map::crypt::{Crypt, Recovery};
+use salt_map::key_store::{KeyStore, FORMAT_VERSION};
+use salt_map::Password;
+use salt_map::secret::Source;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process;
 
-fn enc(pass: &str, path: &str) -> Result<Option<bool>, std::io::Error> {
-    let mut crypt = match Crypt::init(pass, path)? {
-        Some(c) => c,
-        None    => return Ok(Some(false)),
+// what every command shares: the keystore to use instead of the
+// per-directory ones, and where passwords come from
+struct Cli<'a> {
+    ks: Option<&'a str>,
+    keyfile: Option<Keyfile>,
+    from: Option<Source>,
+    new_from: Option<Source>,
+}
+
+impl<'a> Cli<'a> {
+    // from --password-from, otherwise the terminal without echo. never
+    // from argv, where it would show up in ps and shell history
+    fn password(&self, prompt: &str) -> salt_map::Result<Password> {
+        match self.from {
+            Some(ref src) => src.read(),
+            None          => tty(prompt),
+        }
+    }
+
+    // a new password. typed ones are asked for twice, since a typo
+    // would lock it in
+    fn new_password(&self, src: Option<&Source>, prompt: &str) -> salt_map::Result<Password> {
+        if let Some(src) = src {
+            return src.read()
+        }
+
+        let first = tty(prompt)?;
+
+        if tty("confirm password: ")?.as_bytes() != first.as_bytes() {
+            eprintln!("error: passwords don't match");
+            process::exit(2);
+        }
+
+        Ok(first)
+    }
+
+    // the password for the keystore at ks_path, a new one when using
+    // it would create the keystore
+    fn unlock(&self, ks_path: &str) -> salt_map::Result<Password> {
+        match std::fs::metadata(ks_path) {
+            Ok(_)   => self.password("password: "),
+            Err(_)  => self.new_password(self.from.as_ref(), "new keystore password: "),
+        }
+    }
+}
+
+fn tty(prompt: &str) -> salt_map::Result<Password> {
+    Password::new(rpassword::read_password_from_tty(Some(prompt))?)
+}
+
+// --keystore, or SALT_MAP_KEYSTORE, points every file at one central
+// keystore, otherwise each directory keeps its own
+fn keystore_for(path: &str, ks: Option<&str>) -> String {
+    match ks {
+        Some(k) => String::from(k),
+        None    => salt_map::crypt::keystore_for(path),
+    }
+}
+
+// on stderr, so it's seen whatever the command goes on to do
+fn warn_corrupt(ks: KeyStore) -> KeyStore {
+    if !ks.corrupt_slots().is_empty() {
+        eprintln!("warning: keystore slots {:?} failed authentication", ks.corrupt_slots());
+    }
+    if ks.degraded() {
+        eprintln!("warning: keystore is read-only until `obx compact` drops them");
+    }
+
+    ks
+}
+
+fn open(pass: &Password, path: &str, c: &Cli) -> salt_map::Result<Crypt> {
+    let ks = KeyStore::new_with(pass,
+                                c.keyfile.as_ref(),
+                                &keystore_for(path, c.ks),
+                                &KdfParams::default())?;
+
+    Crypt::with_keystore(warn_corrupt(ks), path)
+}
+
+// opening an existing keystore for something that reads it: new_from
+// would create one that isn't there
+fn existing(pass: &Password, ks_path: &str, c: &Cli) -> salt_map::Result<KeyStore> {
+    present(ks_path)?;
+
+    KeyStore::new_with(pass, c.keyfile.as_ref(), ks_path, &KdfParams::default())
+        .map(warn_corrupt)
+}
+
+// file commands other than encrypt never create a keystore
+fn existing_keystore(path: &str, ks: Option<&str>) -> salt_map::Result<()> {
+    present(&keystore_for(path, ks))
+}
+
+// a missing keystore by name, rather than as a bare ENOENT
+fn present(ks_path: &str) -> salt_map::Result<()> {
+    match std::fs::metadata(ks_path) {
+        Ok(_)      => Ok(()),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound
+                   => Err(salt_map::Error::NoKeystore(String::from(ks_path))),
+        Err(e)     => Err(e.into()),
+    }
+}
+
+// what a directory operation does to each file under it
+#[derive(Clone, Copy)]
+enum Tree {
+    Encrypt,
+    Decrypt,
+    Verify,
+}
+
+// sidecars the tool writes next to a file, named after it
+const SIDECARS: [(&str, &str); 3] = [
+    (".tags",    "tags of the file beside it"),
+    (".journal", "journal of the file beside it"),
+    (".tmp",     "unfinished write of the file beside it"),
+];
+
+// resolved through the parent when p doesn't exist yet, as a keystore
+// a tree encrypt is about to create doesn't
+fn canonical(p: &Path) -> PathBuf {
+    salt_map::crypt::canonical(p).unwrap_or_else(|_| p.to_path_buf())
+}
+
+// the keystore a tree runs against, then the files written beside it
+fn keystore_files(ks_path: &str) -> Vec<PathBuf> {
+    let ks = canonical(Path::new(ks_path));
+
+    let mut out = vec![ks.clone()];
+    for (suffix, _) in &SIDECARS {
+        let mut p = ks.clone().into_os_string();
+        p.push(suffix);
+        out.push(PathBuf::from(p));
+    }
+    out.push(PathBuf::from(salt_map::key_store::lock_path(ks.to_str().unwrap_or(ks_path))));
+
+    out
+}
+
+// why a file under a tree is left alone, if it is: the keystore in use
+// and its working files wherever they sit, other keystores, and the
+// sidecars of files that are there beside them. anything else named
+// like a sidecar is an ordinary file
+fn excluded(p: &Path, keystore: &[PathBuf]) -> Option<&'static str> {
+    let name = p.file_name().and_then(|n| n.to_str()).unwrap_or("");
+
+    for (i, k) in keystore.iter().enumerate() {
+        if k.file_name() == p.file_name() && *k == canonical(p) {
+            return Some(if i == 0 { "the keystore in use" } else { "working file of the keystore in use" })
+        }
+    }
+
+    if name == ".keystore" {
+        return Some("a keystore")
+    }
+
+    if name == salt_map::key_store::lock_path(".keystore") {
+        return Some("lock of a keystore")
+    }
+
+    for &(suffix, why) in &SIDECARS {
+        if name.len() > suffix.len() && name.ends_with(suffix) {
+            let base = p.with_file_name(&name[..name.len() - suffix.len()]);
+
+            if base.symlink_metadata().is_ok() {
+                return Some(why)
+            }
+        }
+    }
+
+    None
+}
+
+// every regular file under dir in path order, and what was passed
+// over and why. symlinks are never followed, so a tree can't reach
+// files outside itself or visit one twice
+fn walk(dir: &Path,
+        keystore: &[PathBuf],
+        files: &mut Vec<String>,
+        skipped: &mut Vec<(String, &'static str)>)
+  -> std::io::Result<()>
+{
+    let mut ents = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    ents.sort_by_key(|e| e.path());
+
+    for e in ents {
+        let p  = e.path();
+        let ft = e.file_type()?;
+
+        let name = match p.to_str() {
+            Some(n) => String::from(n),
+            None    => {
+                skipped.push((p.to_string_lossy().into_owned(), "path isn't valid utf-8"));
+                continue
+            },
+        };
+
+        if ft.is_symlink() {
+            skipped.push((name, "symlink, not followed"));
+        } else if ft.is_dir() {
+            walk(&p, keystore, files, skipped)?;
+        } else if !ft.is_file() {
+            skipped.push((name, "not a regular file"));
+        } else if let Some(why) = excluded(&p, keystore) {
+            skipped.push((name, why));
+        } else {
+            files.push(name);
+        }
+    }
+
+    Ok(())
+}
+
+// runs op over every file under dir in parallel, on one password, and
+// prints a line per file. each file goes to the keystore the single file
+// commands would use for it, so the one in its own directory unless
+// --keystore is given. returns how many files failed
+fn tree(dir: &str, c: &Cli, op: Tree) -> salt_map::Result<usize> {
+    let in_use = c.ks.map(keystore_files).unwrap_or_default();
+
+    let mut files   = Vec::new();
+    let mut skipped = Vec::new();
+    walk(Path::new(dir), &in_use, &mut files, &mut skipped)?;
+
+    let mut groups: BTreeMap<String, Vec<&String>> = BTreeMap::new();
+    for f in &files {
+        groups.entry(keystore_for(f, c.ks)).or_default().push(f);
+    }
+
+    // a password that creates a keystore is confirmed, and has to open
+    // any that are already there too
+    let creates = match op {
+        Tree::Encrypt => groups.keys().any(|k| std::fs::metadata(k).is_err()),
+        _             => false,
+    };
+    let pass = match (groups.is_empty(), creates) {
+        (true, _)      => None,
+        (false, true)  => Some(c.new_password(c.from.as_ref(), "new keystore password: ")?),
+        (false, false) => Some(c.password("password: ")?),
     };
-    Ok(crypt.encrypt()?)
+
+    // only reads are left without a keystore, each of their files fails on it
+    let mut stores = Vec::new();
+    for (k, fs) in &groups {
+        let pass = pass.as_ref().unwrap();
+
+        let ks = match (op, std::fs::metadata(k).is_ok()) {
+            (Tree::Encrypt, _) => Some(warn_corrupt(KeyStore::new_with(pass,
+                                                                       c.keyfile.as_ref(),
+                                                                       k,
+                                                                       &KdfParams::default())?)),
+            (_, true)          => Some(existing(pass, k, c)?),
+            (_, false)         => None,
+        };
+
+        stores.push((k, fs, ks));
+    }
+
+    let jobs: Vec<(&String, &String, Option<&KeyStore>)> = stores.iter()
+        .flat_map(|&(k, fs, ref ks)| fs.iter().map(move |&f| (k, f, ks.as_ref())))
+        .collect();
+
+    let mut results: Vec<(&String, salt_map::Result<&str>)> = jobs.par_iter()
+        .map(|&(k, f, ks)| {
+            let ks = match ks {
+                Some(ks) => ks,
+                None     => return (f, Err(salt_map::Error::NoKeystore(k.clone()))),
+            };
+
+            let r = ks.share()
+                .and_then(|s| Crypt::with_keystore(s, f))
+                .and_then(|mut c| match op {
+                    Tree::Encrypt => c.encrypt().map(|()| "encrypted"),
+                    Tree::Decrypt => c.decrypt().map(|()| "decrypted"),
+                    Tree::Verify  => c.verify().map(|()| "intact"),
+                });
+
+            (f, r)
+        })
+        .collect();
+    results.sort_by(|a, b| a.0.cmp(b.0));
+
+    let mut failed = 0;
+    for (f, r) in &results {
+        match *r {
+            Ok(done)   => println!("{}: {}", f, done),
+            Err(ref e) => { failed += 1; println!("{}: error: {}", f, e) },
+        }
+    }
+    for (f, why) in &skipped {
+        println!("{}: skipped, {}", f, why);
+    }
+
+    println!("result: {} files, {} failed, {} skipped", results.len(), failed, skipped.len());
+
+    Ok(failed)
+}
+
+// in place, unless a destination is given
+fn encrypt(m: &ArgMatches, c: &Cli) -> salt_map::Result<i32> {
+    let path = m.value_of("path").unwrap();
+
+    if Path::new(path).is_dir() {
+        return tree(path, c, Tree::Encrypt).map(|f| (f > 0) as i32)
+    }
+
+    let mut crypt = open(&c.unlock(&keystore_for(path, c.ks))?, path, c)?;
+
+    match m.value_of("to") {
+        Some(d) => crypt.encrypt_to(d)?,
+        None    => crypt.encrypt()?,
+    }
+
+    println!("result: encrypted");
+    Ok(0)
+}
+
+fn decrypt(m: &ArgMatches, c: &Cli) -> salt_map::Result<i32> {
+    let path = m.value_of("path").unwrap();
+
+    if Path::new(path).is_dir() {
+        return tree(path, c, Tree::Decrypt).map(|f| (f > 0) as i32)
+    }
+
+    existing_keystore(path, c.ks)?;
+    let mut crypt = open(&c.password("password: ")?, path, c)?;
+
+    match m.value_of("to") {
+        Some(d) => crypt.decrypt_to(d)?,
+        None    => crypt.decrypt()?,
+    }
+
+    println!("result: decrypted");
+    Ok(0)
+}
+
+fn verify(m: &ArgMatches, c: &Cli) -> salt_map::Result<i32> {
+    let path = m.value_of("path").unwrap();
+
+    if Path::new(path).is_dir() {
+        return tree(path, c, Tree::Verify).map(|f| (f > 0) as i32)
+    }
+
+    existing_keystore(path, c.ks)?;
+    let report = open(&c.password("password: ")?, path, c)?.authenticate()?;
+
+    if report.intact() {
+        println!("result: intact");
+        return Ok(0)
+    }
+
+    println!("result: {}", report.fault.as_ref().unwrap());
+    report.byte_ranges().iter().for_each(|b| println!("damaged: {}..{}", b.start, b.end));
+
+    Ok(1)
+}
+
+fn status(m: &ArgMatches, c: &Cli) -> salt_map::Result<i32> {
+    let path = m.value_of("path").unwrap();
+
+    existing_keystore(path, c.ks)?;
+    let state = open(&c.password("password: ")?, path, c)?.status()?;
+
+    println!("result: {:?}", state);
+    Ok(0)
+}
+
+fn recover(m: &ArgMatches, c: &Cli) -> salt_map::Result<i32> {
+    let path = m.value_of("path").unwrap();
+    let how  = if m.is_present("rollback") { Recovery::RollBack } else { Recovery::Finish };
+
+    existing_keystore(path, c.ks)?;
+
+    match open(&c.password("password: ")?, path, c)?.recover(how)? {
+        Some(op) => println!("result: recovered interrupted {:?} ({:?})", op, how),
+        None     => println!("result: nothing to recover"),
+    }
+
+    Ok(0)
+}
+
+// moves an encrypted file without losing its entry
+fn mv(m: &ArgMatches, c: &Cli) -> salt_map::Result<i32> {
+    let path = m.value_of("path").unwrap();
+    let dest = m.value_of("dest").unwrap();
+
+    existing_keystore(path, c.ks)?;
+    open(&c.password("password: ")?, path, c)?.rename(dest)?;
+
+    println!("result: moved to {}", dest);
+    Ok(0)
+}
+
+// for files moved with something other than mv
+fn reattach(m: &ArgMatches, c: &Cli) -> salt_map::Result<i32> {
+    let path = m.value_of("path").unwrap();
+
+    existing_keystore(path, c.ks)?;
+
+    match open(&c.password("password: ")?, path, c)?.reattach()? {
+        Some(p) => { println!("result: reattached the entry for {}", p); Ok(0) },
+        None    => { println!("result: no entry matches the file"); Ok(1) },
+    }
+}
+
+fn list(ks_path: &str, c: &Cli) -> salt_map::Result<i32> {
+    let ks = existing(&c.password("password: ")?, ks_path, c)?;
+
+    for e in ks.entries()? {
+        match e.path() {
+            []  => println!("<unrecorded path>"),
+            p   => println!("{}", String::from_utf8_lossy(p)),
+        }
+    }
+
+    Ok(0)
+}
+
+// drops entries that don't authenticate and duplicates, which is also
+// how a degraded keystore is made writable again
+fn compact(ks_path: &str, c: &Cli) -> salt_map::Result<i32> {
+    let mut ks = existing(&c.password("password: ")?, ks_path, c)?;
+
+    println!("result: dropped {} entries", ks.compact()?);
+    Ok(0)
 }
 
-fn dec(pass: &str, path: &str) -> Result<Option<bool>, std::io::Error> {
-    let mut crypt = match Crypt::init(pass, path)? {
-        Some(c) => c,
-        None    => return Ok(Some(false)),
+// the keyfile stays as it was unless --new-keyfile or --drop-keyfile
+fn passwd(m: &ArgMatches, ks_path: &str, c: &Cli) -> salt_map::Result<i32> {
+    let old    = c.password("current password: ")?;
+    let mut ks = existing(&old, ks_path, c)?;
+    let new    = c.new_password(c.new_from.as_ref(), "new password: ")?;
+
+    let new_key = match m.value_of("new-keyfile") {
+        Some(k) => Some(Keyfile::read(k)?),
+        None    => None,
     };
-    Ok(crypt.decrypt()?)
+    let keep = !m.is_present("drop-keyfile") && new_key.is_none();
+
+    ks.change_credentials(&old,
+                          c.keyfile.as_ref(),
+                          &new,
+                          if keep { c.keyfile.as_ref() } else { new_key.as_ref() })?;
+
+    println!("result: password changed");
+    Ok(0)
+}
+
+fn migrate(ks_path: &str, c: &Cli) -> salt_map::Result<i32> {
+    present(ks_path)?;
+
+    let pass = c.password("password: ")?;
+
+    match salt_map::migrate::migrate_with(&pass, c.keyfile.as_ref(), ks_path, &KdfParams::default())? {
+        v if v == FORMAT_VERSION
+              => println!("result: already at format version {}", v),
+        v     => println!("result: migrated from format version {}", v),
+    }
+
+    Ok(0)
+}
+
+// kind:value, checked up front so a typo fails before anything runs
+fn source(s: String) -> Result<(), String> {
+    match Source::parse(&s) {
+        Some(_) => Ok(()),
+        None    => Err(String::from("expected fd:N, file:PATH, env:NAME or cmd:COMMAND")),
+    }
+}
+
+fn cli() -> App<'static, 'static> {
+    let path = || Arg::with_name("path")
+        .help("file, or directory to walk")
+        .required(true);
+    let file = || Arg::with_name("path")
+        .help("file")
+        .required(true);
+    let to = || Arg::with_name("to")
+        .long("to")
+        .value_name("DEST")
+        .help("write to DEST and leave the file as it is");
+
+    App::new("obx")
+        .about("encrypts and authenticates files against a password protected keystore")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("keystore")
+             .long("keystore")
+             .value_name("PATH")
+             .env("SALT_MAP_KEYSTORE")
+             .global(true)
+             .help("keystore to use instead of the one next to each file"))
+        .arg(Arg::with_name("keyfile")
+             .long("keyfile")
+             .value_name("PATH")
+             .global(true)
+             .help("keyfile the keystore needs along with the password, \
+                    or will need if this creates it"))
+        .arg(Arg::with_name("password-from")
+             .long("password-from")
+             .value_name("SOURCE")
+             .validator(source)
+             .global(true)
+             .help("read the password from fd:N, file:PATH, env:NAME or cmd:COMMAND \
+                    instead of asking for it"))
+        .subcommand(SubCommand::with_name("encrypt")
+                    .about("encrypts a file, or every file under a directory")
+                    .arg(path())
+                    .arg(to()))
+        .subcommand(SubCommand::with_name("decrypt")
+                    .about("decrypts a file, or every file under a directory")
+                    .arg(path())
+                    .arg(to()))
+        .subcommand(SubCommand::with_name("verify")
+                    .about("checks a file, or every file under a directory, against its tag")
+                    .arg(path()))
+        .subcommand(SubCommand::with_name("status")
+                    .about("shows whether a file is encrypted")
+                    .arg(file()))
+        .subcommand(SubCommand::with_name("recover")
+                    .about("finishes an encrypt or decrypt that was cut short")
+                    .arg(file())
+                    .arg(Arg::with_name("rollback")
+                         .long("rollback")
+                         .help("undo it instead")))
+        .subcommand(SubCommand::with_name("mv")
+                    .about("moves an encrypted file along with its entry")
+                    .arg(file())
+                    .arg(Arg::with_name("dest").required(true)))
+        .subcommand(SubCommand::with_name("reattach")
+                    .about("finds the entry of a file that was moved without mv")
+                    .arg(file()))
+        .subcommand(SubCommand::with_name("list")
+                    .about("lists the files a keystore has entries for"))
+        .subcommand(SubCommand::with_name("compact")
+                    .about("drops a keystore's corrupt and duplicate entries"))
+        .subcommand(SubCommand::with_name("passwd")
+                    .about("changes a keystore's password")
+                    .arg(Arg::with_name("new-password-from")
+                         .long("new-password-from")
+                         .value_name("SOURCE")
+                         .validator(source)
+                         .help("read the new password from SOURCE, like --password-from"))
+                    .arg(Arg::with_name("new-keyfile")
+                         .long("new-keyfile")
+                         .value_name("PATH")
+                         .conflicts_with("drop-keyfile")
+                         .help("require this keyfile from now on"))
+                    .arg(Arg::with_name("drop-keyfile")
+                         .long("drop-keyfile")
+                         .help("stop requiring a keyfile")))
+        .subcommand(SubCommand::with_name("migrate")
+                    .about("upgrades a keystore to the current format"))
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let m = cli().get_matches();
 
-    let mode = args[1].clone();
-    let pass = args[2].clone();
-    let path = args[3].clone();
+    let (name, sub) = m.subcommand();
+    let sub = sub.unwrap();
 
-    println!("mode: {}\npass: {}\npath: {}", mode, pass, path);
+    let keyfile = match sub.value_of("keyfile").map(Keyfile::read) {
+        Some(Ok(k))  => Some(k),
+        Some(Err(e)) => {
+            eprintln!("error: couldn't read keyfile: {}", e);
+            process::exit(1);
+        },
+        None         => None,
+    };
 
-    if mode == "e" {
-        match enc(&pass, &path) {
-            Ok(Some(r)) => println!("result: {}", r),
-            Ok(None)    => println!("none result"),
-            Err(e)      => println!("error:\n{:?}", e),
-        }
-    } else if mode == "d" {
-        match dec(&pass, &path) {
-            Ok(Some(r)) => println!("result: {}", r),
-            Ok(None)    => println!("none result"),
-            Err(e)      => println!("error:\n{:?}", e),
+    let c = Cli {
+        ks: sub.value_of("keystore"),
+        keyfile,
+        from: sub.value_of("password-from").and_then(Source::parse),
+        new_from: sub.value_of("new-password-from").and_then(Source::parse),
+    };
+
+    // the keystore commands act on --keystore, or the one here
+    let ks_path = c.ks.unwrap_or(".keystore");
+
+    let code = match name {
+        "encrypt"  => encrypt(sub, &c),
+        "decrypt"  => decrypt(sub, &c),
+        "verify"   => verify(sub, &c),
+        "status"   => status(sub, &c),
+        "recover"  => recover(sub, &c),
+        "mv"       => mv(sub, &c),
+        "reattach" => reattach(sub, &c),
+        "list"     => list(ks_path, &c),
+        "compact"  => compact(ks_path, &c),
+        "passwd"   => passwd(sub, ks_path, &c),
+        "migrate"  => migrate(ks_path, &c),
+        _          => unreachable!(),
+    };
+
+    match code {
+        Ok(c)  => process::exit(c),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            process::exit(1);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk_exclusions() {
+        let dir = std::env::temp_dir().join("salt_map_obx_walk");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+
+        for f in &["central.keystore", "central.keystore.tmp", "a.txt", "a.txt.tags",
+                   "notes.tmp", "foo.tags", "sub/.keystore", "sub/b.journal", "sub/b",
+                   "central.keystore.lock", "sub/.keystore.lock"] {
+            std::fs::write(dir.join(f), b"x").unwrap();
         }
+
+        // reached through a different spelling than the walk uses
+        let ks = dir.join("sub/../central.keystore");
+
+        let mut files   = Vec::new();
+        let mut skipped = Vec::new();
+        walk(&dir, &keystore_files(ks.to_str().unwrap()), &mut files, &mut skipped).unwrap();
+
+        let name = |f: &str| dir.join(f).to_str().unwrap().to_string();
+        let why  = |f: &str| skipped.iter().find(|s| s.0 == name(f)).map(|s| s.1);
+
+        assert!(files == vec![name("a.txt"), name("foo.tags"), name("notes.tmp"), name("sub/b")]);
+
+        assert!(why("central.keystore") == Some("the keystore in use"));
+        assert!(why("central.keystore.tmp") == Some("working file of the keystore in use"));
+        assert!(why("a.txt.tags") == Some("tags of the file beside it"));
+        assert!(why("sub/.keystore") == Some("a keystore"));
+        assert!(why("sub/b.journal") == Some("journal of the file beside it"));
+        assert!(why("central.keystore.lock") == Some("working file of the keystore in use"));
+        assert!(why("sub/.keystore.lock") == Some("lock of a keystore"));
+        assert!(skipped.len() == 7);
     }
 }
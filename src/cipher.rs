@@ -1,5 +1,5 @@
-/// this module is mainly for convenience structs and functions
-/// associated with parallel authenticated en/decryption
+//! this module is mainly for convenience structs and functions
+//! associated with parallel authenticated en/decryption
 
 // contains 'shared' cipher and auth states plus
 // a key for use with the final keccak hmac
@@ -21,42 +21,159 @@ impl Drop for Cipher {
     }
 }
 
+// argon2 cost parameters, chosen when a keystore is created
+// and stored in its header so it can be reopened by any build
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KdfParams {
+    pub mem_cost: u32, // KiB
+    pub time_cost: u32,
+    pub lanes: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> KdfParams {
+        KdfParams {
+            mem_cost: 64*1024,
+            time_cost: 3,
+            lanes: 2,
+        }
+    }
+}
+
+// bounds on params read back from a header, which is only authenticated
+// after argon2 has run. 4 GiB and 64 passes/lanes are far past anything
+// a keystore is created with, but keep a doctored header from aborting
+// the process on an allocation or stalling it for hours
+pub const MAX_MEM_COST: u32 = 4*1024*1024;
+pub const MAX_TIME_COST: u32 = 64;
+pub const MAX_LANES: u32 = 64;
+
+impl KdfParams {
+    pub fn check(&self)
+      -> ::Result<()>
+    {
+        if  self.lanes == 0 ||
+            self.lanes > MAX_LANES ||
+            self.time_cost == 0 ||
+            self.time_cost > MAX_TIME_COST ||
+            self.mem_cost < 8*self.lanes ||
+            self.mem_cost > MAX_MEM_COST
+            {
+                return Err(::Error::InvalidKdfParams);
+            }
+
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> [u8; 12] {
+        let mut b = [0u8; 12];
+
+        b[0..4].clone_from_slice(&self.mem_cost.to_le_bytes());
+        b[4..8].clone_from_slice(&self.time_cost.to_le_bytes());
+        b[8..12].clone_from_slice(&self.lanes.to_le_bytes());
+
+        b
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Option<KdfParams> {
+        if raw.len() != 12 { return None }
+
+        let mut w = [0u8; 4];
+        let mut word = |i: usize| {
+            w.clone_from_slice(&raw[i..i+4]);
+            u32::from_le_bytes(w)
+        };
+
+        Some(KdfParams {
+            mem_cost: word(0),
+            time_cost: word(4),
+            lanes: word(8),
+        })
+    }
+}
+
+// size of a cipher's raw key material: keys 32 | nons 24 | auth 16 | afin 16
+pub const RAW_LEN: usize = 88;
+
+// blake2b of a keyfile's contents, handed to argon2 as its secret input
+// so a keystore takes both the password and the file to open. any file
+// will do, only its digest is kept
+pub struct Keyfile([u8; 64]);
+
+impl Keyfile {
+    pub fn read(path: &str)
+      -> ::Result<Keyfile>
+    {
+        use std::io::Read;
+
+        let mut f = ::std::fs::File::open(path)?;
+        let mut h = ::blake2_rfc::blake2b::Blake2b::new(64);
+
+        let mut buf = [0u8; 64*1024];
+        let mut len = 0;
+        loop {
+            let n = f.read(&mut buf)?;
+            if n == 0 { break }
+
+            h.update(&buf[..n]);
+            len += n;
+        }
+        ::memzero(&mut buf);
+
+        if len == 0
+        { return Err(::Error::Io(::std::io::Error::new(::std::io::ErrorKind::InvalidData, "keyfile is empty"))) }
+
+        let mut k = Keyfile([0u8; 64]);
+        k.0.clone_from_slice(h.finalize().as_bytes());
+
+        Ok(k)
+    }
+}
+
+impl Drop for Keyfile {
+    fn drop(&mut self) {
+        ::memzero(&mut self.0);
+    }
+}
+
 impl Cipher {
-    fn from_vecs(crypt_raw: &[u8], auth_raw: &[u8]) -> Option<Cipher> {
-        if crypt_raw.len() < 56 || auth_raw.len() < 32 { return None }
-
-        Some(Cipher {
-            keys: ::CryptKey::from_slice(&crypt_raw[0..32]).unwrap(),
-            nons: ::CryptNon::from_slice(&crypt_raw[32..56]).unwrap(),
-            auth: ::AuthKey::from_slice(&auth_raw[0..16]).unwrap(),
-            afin: ::AuthKey::from_slice(&auth_raw[16..32]).unwrap(),
+    pub fn from_vecs(crypt_raw: &[u8], auth_raw: &[u8]) -> ::Result<Cipher> {
+        if crypt_raw.len() < 56 || auth_raw.len() < 32 { return Err(::Error::InvalidLength) }
+
+        Ok(Cipher {
+            keys: ::CryptKey::from_slice(&crypt_raw[0..32]).ok_or(::Error::InvalidLength)?,
+            nons: ::CryptNon::from_slice(&crypt_raw[32..56]).ok_or(::Error::InvalidLength)?,
+            auth: ::AuthKey::from_slice(&auth_raw[0..16]).ok_or(::Error::InvalidLength)?,
+            afin: ::AuthKey::from_slice(&auth_raw[16..32]).ok_or(::Error::InvalidLength)?,
         })
     }
 
-    pub fn from_argon(password: &str, crypt_salt: &[u8], auth_salt: &[u8], mem: u32) -> Option<Cipher> {
+    // password is at least MIN_PASSWORD by construction
+    pub fn from_argon(password: &::Password, keyfile: Option<&Keyfile>, crypt_salt: &[u8], auth_salt: &[u8], params: &KdfParams) -> ::Result<Cipher> {
         if  crypt_salt.len() < 16 ||
-            auth_salt.len() < 16  ||
-            password.len() < 16
+            auth_salt.len() < 16
             {
-                return None;
+                return Err(::Error::InvalidLength);
             }
 
+        params.check()?;
+
         use argon2::{Config, ThreadMode, Variant, Version};
 
         let ac = Config {
             ad: &[],
             hash_length: 64,
-            lanes: 2,
-            mem_cost: mem,
-            secret: &[],
+            lanes: params.lanes,
+            mem_cost: params.mem_cost,
+            secret: keyfile.map(|k| &k.0[..]).unwrap_or(&[]),
             thread_mode: ThreadMode::Parallel,
-            time_cost: 3,
+            time_cost: params.time_cost,
             variant: Variant::Argon2id,
             version: Version::Version13,
         };
 
-        let mut craw: Vec<u8> = ::argon2::hash_raw(password.as_bytes(), crypt_salt, &ac).unwrap();
-        let mut araw: Vec<u8> = ::argon2::hash_raw(password.as_bytes(), auth_salt, &ac).unwrap();
+        let mut craw: Vec<u8> = ::argon2::hash_raw(password.as_bytes(), crypt_salt, &ac)?;
+        let mut araw: Vec<u8> = ::argon2::hash_raw(password.as_bytes(), auth_salt, &ac)?;
 
         let c = Cipher::from_vecs(&craw[..], &araw[..]);
 
@@ -66,6 +183,29 @@ impl Cipher {
         c
     }
 
+    // fresh key material straight from the rng, used for per-file
+    // keys and a keystore's master key
+    pub fn random() -> ::Result<Cipher> {
+        let mut raw = ::random(RAW_LEN);
+        let c = Cipher::from_vecs(&raw[0..56], &raw[56..RAW_LEN]);
+
+        ::memzero(&mut raw);
+
+        c
+    }
+
+    // copies the raw key material into out, which must be RAW_LEN bytes
+    pub fn write_raw(&self, out: &mut [u8]) -> bool {
+        if out.len() != RAW_LEN { return false }
+
+        out[0..32].clone_from_slice(&self.keys[..]);
+        out[32..56].clone_from_slice(&self.nons[..]);
+        out[56..72].clone_from_slice(&self.auth[..]);
+        out[72..88].clone_from_slice(&self.afin[..]);
+
+        true
+    }
+
     pub fn auth(&self) -> &[u8] {
         &self.auth.0[..]
     }
@@ -75,13 +215,40 @@ impl Cipher {
     }
 }
 
-pub fn align(length: usize)
+// chunk size for entries that don't record one
+pub const DEFAULT_CHUNK: usize = 1024*1024;
+
+// chunks are whole keystream blocks, so counters land on chunk starts
+pub const MIN_CHUNK: usize = 64;
+pub const MAX_CHUNK: usize = 1024*1024*1024;
+
+pub fn check_chunk(chunk: usize)
+  -> ::Result<()>
+{
+    if !(MIN_CHUNK..=MAX_CHUNK).contains(&chunk) || !chunk.is_multiple_of(64)
+    { return Err(::Error::InvalidChunkSize) }
+
+    Ok(())
+}
+
+// number of chunks covering length bytes
+pub fn align(length: usize,
+             chunk: usize)
   -> usize
 {
-    if (length % (1024 * 1024)) == 0 {
-        return length / (1024 * 1024)
+    if length.is_multiple_of(chunk) {
+        return length / chunk
     }
-    (length / (1024 * 1024)) + 1
+    (length / chunk) + 1
+}
+
+// xchacha20 block counter a chunk starts at
+#[inline]
+pub fn ic(idx: usize,
+          chunk: usize)
+  -> u64
+{
+    idx as u64 * (chunk as u64 / 64)
 }
 
 impl ::std::fmt::Debug for Cipher {
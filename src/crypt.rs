@@ -1,277 +1,753 @@
 /// file auth crypt using keystore type
-use blake2_rfc::blake2b::Blake2b as Blake2b;
 use rayon::prelude::*;
-use std::fs::OpenOptions;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use ::chashmap::CHashMap;
 use ::cipher::Cipher as Cipher;
+use ::cipher::KdfParams as KdfParams;
+use ::journal::Journal as Journal;
+use ::journal::Op as Op;
+use ::key_store::Entry as Entry;
 use ::key_store::KeyStore as KeyStore;
-
-// impl zeroing password type
+use ::key_store::State as State;
+use ::stream::DecryptReader as DecryptReader;
+use ::stream::EncryptWriter as EncryptWriter;
+use ::tag::Report as Report;
+use ::tag::Seal as Seal;
+
+// what recover does with an interrupted encrypt or decrypt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    Finish,
+    RollBack,
+}
 
 pub struct Crypt {
     path: String,
+    id: String,
     ciph: Cipher,
     meta: KeyStore,
     name_tag: ::KTag,
     authenticated: Option<bool>,
+    chunk: usize,
 }
 
-impl Crypt {
-    pub fn init(pass: &str,
+// canonical form of a path that may not exist yet, like a destination,
+// going through its directory instead
+pub fn canonical(path: &Path)
+  -> ::Result<PathBuf>
+{
+    match ::std::fs::canonicalize(path) {
+        Ok(c)  => Ok(c),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
+            let file = path.file_name()
+                .ok_or_else(|| ::std::io::Error::new(::std::io::ErrorKind::InvalidInput,
+                                                     "path doesn't name a file"))?;
+
+            let dir = match path.parent() {
+                Some(d) if !d.as_os_str().is_empty() => d,
+                _                                    => Path::new("."),
+            };
+
+            Ok(::std::fs::canonicalize(dir)?.join(file))
+        },
+        Err(e) => Err(::Error::Io(e)),
+    }
+}
+
+// what a keystore names path by: canonical and relative to the
+// keystore's directory, so every spelling of a file finds the same
+// entry. files outside that directory go by their absolute path
+pub fn identity(ks_path: &str,
                 path: &str)
-      -> Result<Option<Crypt>, ::std::io::Error>
+  -> ::Result<String>
+{
+    let root = canonical(Path::new(ks_path))?;
+    let full = canonical(Path::new(path))?;
+
+    let id = match root.parent().map(|r| full.strip_prefix(r)) {
+        Some(Ok(rel)) => rel.to_path_buf(),
+        _             => full,
+    };
+
+    id.to_str()
+        .map(String::from)
+        .ok_or_else(|| ::Error::Io(::std::io::Error::new(::std::io::ErrorKind::InvalidData,
+                                                         "path isn't valid utf-8")))
+}
+
+// the per-directory keystore Crypt::init uses for path
+pub fn keystore_for(path: &str)
+  -> String
+{
+    let cwd = match path.rfind('/') {
+        Some(x) => String::from(path.split_at(x).0) + "/", // windows issues ???
+        None    => String::from(""),
+    };
+
+    cwd + ".keystore"
+}
+
+// chunks per journaled batch, one per thread while that stays small
+fn batch_for(chunk: usize)
+  -> u64
+{
+    let cap = ::std::cmp::max(1, 64*1024*1024 / chunk);
+
+    ::std::cmp::min(::rayon::current_num_threads(), cap) as u64
+}
+
+// a new file only the owner can read, whatever the umask, since it's
+// about to hold plaintext or keys to it. one left over is replaced
+fn create_private(path: &str)
+  -> ::std::io::Result<File>
+{
+    if let Err(e) = ::std::fs::remove_file(path) {
+        if e.kind() != ::std::io::ErrorKind::NotFound
+        { return Err(e) }
+    }
+
+    let mut opts = OpenOptions::new();
+    opts.write(true).create_new(true);
+
+    #[cfg(unix)]
     {
-        let cwd = match path.rfind("/") {
-            Some(x) => String::from(path.split_at(x).0) + "/", // windows issues ???
-            None    => String::from(""),
-        };
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
 
-        let ks_path = String::from(cwd) + ".keystore";
+    opts.open(path)
+}
 
-        let mut ks = match KeyStore::new_from(pass, &ks_path)? {
-            Some(x) => x,
-            None    => return Ok(None),
-        };
+// writes tmp through fill and syncs it, hands fill's result to commit,
+// then moves tmp over dest. dest is left alone and tmp removed if fill
+// fails, and once commit has run a crash leaves a complete tmp behind
+fn write_through<T, F, C>(dest: &str,
+                          fill: F,
+                          commit: C)
+  -> ::Result<()>
+  where F: FnOnce(&mut File) -> ::Result<T>,
+        C: FnOnce(T) -> ::Result<()>
+{
+    let tmp = String::from(dest) + ".tmp";
+
+    let written = create_private(&tmp)
+        .map_err(::Error::from)
+        .and_then(|mut f| {
+            let t = fill(&mut f)?;
+            f.sync_all()?;
+            Ok(t)
+        })
+        .and_then(commit);
+
+    if let Err(e) = written {
+        let _ = ::std::fs::remove_file(&tmp);
+        return Err(e)
+    }
 
-        if ks.authenticated == false { return Ok(None) }
+    ::std::fs::rename(&tmp, dest)?;
 
-        let mut name_hash = ::KTag([0u8; 64]);
+    Ok(())
+}
 
-        let mut h = ::Keccak::new_keccak512();
+impl Crypt {
+    // uses the keystore next to path, params only apply when the
+    // directory's keystore is created
+    pub fn init(pass: &::Password,
+                path: &str,
+                params: &KdfParams)
+      -> ::Result<Crypt>
+    {
+        Crypt::init_in(pass, path, &keystore_for(path), params)
+    }
 
-        h.update(&ks.get_own_final()[..]);
-        h.update(path.as_bytes());
-        h.finalize(&mut name_hash.0);
+    // uses the keystore at ks_path wherever path is, so one keystore
+    // can manage files across directories
+    pub fn init_in(pass: &::Password,
+                   path: &str,
+                   ks_path: &str,
+                   params: &KdfParams)
+      -> ::Result<Crypt>
+    {
+        let ks = KeyStore::new_from(pass, ks_path, params)?;
 
-        let is = ks.get_entry(&*name_hash)?;
+        Crypt::with_keystore(ks, path)
+    }
 
-        if is.is_none() {
-            let csalt = ::AuthKey::from_slice(&mut ::random(16))
-                .expect("rng error");
-            let asalt = ::AuthKey::from_slice(&mut ::random(16))
-                .expect("rng error");
-            let hmac  = ::KTag([0u8; 64]);
+    // takes an already unlocked keystore, so many files can share one
+    // argon2 unlock. into_keystore hands it back
+    pub fn with_keystore(mut ks: KeyStore,
+                         path: &str)
+      -> ::Result<Crypt>
+    {
+        let id = identity(&ks.backing, path)?;
 
-            ks.add_entry(&*name_hash, &*csalt, &*asalt, &*hmac)?;
+        let mut name_hash = ks.name_of(&id);
+        let mut is = ks.get_entry(&*name_hash)?;
+
+        // entries made before names were canonical went by the path
+        // exactly as it was given
+        if is.is_none() && id != path {
+            let given = ks.name_of(path);
+
+            if let Some(i) = ks.get_entry(&*given)? {
+                name_hash = given;
+                is = Some(i);
+            }
+        }
 
+        // a corrupt entry might be this file's, and a fresh one would
+        // shadow it for good
+        if is.is_none() && !ks.corrupt_slots().is_empty()
+        { return Err(::Error::EntryCorrupt) }
+
+        // files only get an entry once they're encrypted, until then
+        // the keys are placeholders
+        if is.is_none() {
             return
                 Ok(
-                Some(
                 Crypt {
                     path: String::from(path),
-                    ciph: Cipher::from_argon(pass, &*csalt, &*asalt, 64*1024) // change for actual use
-                            .expect("kdf error"),
+                    id,
+                    ciph: Cipher::random()?,
                     meta: ks,
                     name_tag: name_hash,
                     authenticated: None,
+                    chunk: ::cipher::DEFAULT_CHUNK,
                 }
-            ))
+            )
         }
         Ok(
-        Some(
         Crypt {
             path: String::from(path),
-            ciph: Cipher::from_argon(pass,
-                                     ks.get_crypt_key(),
-                                     ks.get_auth_key(),
-                                     64*1024) // change for actual use
-                                     .expect("kdf error"),
+            id,
+            ciph: Cipher::from_vecs(ks.get_crypt_key(),
+                                    ks.get_auth_key())?,
+            chunk: ks.get_chunk(),
             meta: ks,
             name_tag: name_hash,
             authenticated: None,
         }
-        ))
+        )
     }
 
-    pub fn encrypt(&mut self)
-      -> Result<Option<bool>, ::std::io::Error>
+    pub fn into_keystore(self)
+      -> KeyStore
+    { self.meta }
+
+    // keystore slots that failed authentication, see KeyStore::degraded
+    pub fn corrupt_slots(&self)
+      -> &[u64]
+    { self.meta.corrupt_slots() }
+
+    pub fn degraded(&self)
+      -> bool
+    { self.meta.degraded() }
+
+    // a degraded keystore couldn't record what's done to the file,
+    // so nothing that writes starts on one
+    fn writable(&self)
+      -> ::Result<()>
     {
-        let timer = Instant::now();
+        if self.meta.degraded()
+        { return Err(::Error::KeystoreDegraded) }
+
+        Ok(())
+    }
+
+    // makes this file's entry the keystore's current one
+    fn load(&mut self)
+      -> ::Result<()>
+    {
+        let tmp = ::KTag(*self.name_tag);
+
+        if self.meta.get_entry(&tmp[..])?.is_none()
+        { return Err(::Error::EntryNotFound) }
+
+        Ok(())
+    }
+
+    // gives a file that was never encrypted its entry. files are keyed
+    // straight from their entry, the password only ever unlocks the
+    // keystore
+    fn ensure_entry(&mut self)
+      -> ::Result<()>
+    {
+        let tmp = ::KTag(*self.name_tag);
+
+        if self.meta.get_entry(&tmp[..])?.is_some()
+        { return Ok(()) }
 
-        println!("encrypting {}", &self.path);
+        let ciph = Cipher::random()?;
+        let hmac = ::KTag([0u8; 64]);
+
+        let mut raw = [0u8; ::cipher::RAW_LEN];
+        ciph.write_raw(&mut raw);
+
+        let added = self.meta.add_entry(&tmp[..], &raw[0..56], &raw[56..], &*hmac, self.id.as_bytes());
+        ::memzero(&mut raw);
+        added?;
+
+        self.ciph = ciph;
+
+        self.set_state(State::Plain)
+    }
+
+    // the entry's state, or InProgress while a journal is left over.
+    // files without an entry were never encrypted and count as Plain
+    pub fn status(&mut self)
+      -> ::Result<State>
+    {
+        if Journal::exists(&self.path)
+        { return Ok(State::InProgress) }
+
+        let tmp = ::KTag(*self.name_tag);
+
+        if self.meta.get_entry(&tmp[..])?.is_none()
+        { return Ok(State::Plain) }
+
+        Ok(self.meta.get_state())
+    }
+
+    // chunk size for the next encryption, which is recorded in the entry.
+    // files already encrypted keep the size they were encrypted with
+    pub fn set_chunk_size(&mut self,
+                          chunk: usize)
+      -> ::Result<()>
+    {
+        ::cipher::check_chunk(chunk)?;
+
+        self.chunk = chunk;
+
+        Ok(())
+    }
+
+    fn set_state(&mut self,
+                 state: State)
+      -> ::Result<()>
+    {
+        let tmp = ::KTag(*self.name_tag);
+
+        self.meta.update_entry_by_state(&tmp[..], state)
+    }
+
+    // checks the file can be encrypted and gives its entry fresh keys,
+    // making one first if it has none
+    fn rekey(&mut self)
+      -> ::Result<()>
+    {
+        self.writable()?;
+        self.ensure_entry()?;
+
+        match self.status()? {
+            State::InProgress => return Err(::Error::Interrupted),
+            State::Encrypted  => return Err(::Error::AlreadyEncrypted),
+            // entries from before states were kept: a file that still
+            // matches its tag is ciphertext
+            State::Unknown if self.authenticate()?.intact()
+                              => return Err(::Error::AlreadyEncrypted),
+            _                 => (),
+        }
+
+        // never reuse a keystream, every encryption gets new keys
+        let ciph = Cipher::random()?;
+        let tmp  = ::KTag(*self.name_tag);
+
+        let mut raw = [0u8; ::cipher::RAW_LEN];
+        ciph.write_raw(&mut raw);
+
+        // the old seal means nothing under new keys, the entry stays
+        // InProgress until the new one is written
+        let rekeyed = self.meta.rekey_entry(&tmp[..],
+                                            &raw[0..56],
+                                            &raw[56..],
+                                            &Seal::empty(),
+                                            self.chunk,
+                                            State::InProgress);
+        ::memzero(&mut raw);
+        rekeyed?;
+
+        self.ciph = ciph;
+
+        Ok(())
+    }
+
+    pub fn encrypt(&mut self)
+      -> ::Result<()>
+    {
+        // the target has to be there before it gets an entry or new keys
         let f = OpenOptions::new()
             .write(true)
             .read(true)
             .open(&self.path)?;
+        let len = f.metadata()?.len();
+
+        self.rekey()?;
+
+        let timer = Instant::now();
+
+        trace!("encrypting {}", &self.path);
+
+        // an empty file can't be mapped and has nothing to transform,
+        // only its seal changes with the keys
+        if len == 0 {
+            let seal = ::tag::seal(&self.ciph, &[], 0);
+            let tmp  = ::KTag(*self.name_tag);
+
+            self.meta.update_entry_by_seal(&tmp[..],
+                                           &seal)?;
+            self.authenticated = Some(true);
+
+            return self.set_state(State::Encrypted)
+        }
 
         let mut map = unsafe { ::MmapMut::map_mut(&f)? };
 
         let l       = map.len();
-        let aligned = ::cipher::align(l);
+        let chunk   = self.chunk;
+        let aligned = ::cipher::align(l, chunk);
 
-        println!("*fn encrypt:\n    map len: {}, supposed chunk count: {}\n",
+        trace!("*fn encrypt:\n    map len: {}, supposed chunk count: {}\n",
             l,
             aligned);
 
         let hash_store: CHashMap<usize, ::KTag>
             = CHashMap::with_capacity(aligned);
 
-        map.par_chunks_mut(1024*1024).enumerate().for_each(|c| {
-            //let mut t_timer = Instant::now();
+        let mut journal = Journal::create(&self.path,
+                                          Op::Encrypt,
+                                          l as u64,
+                                          chunk as u64,
+                                          0,
+                                          aligned as u64,
+                                          batch_for(chunk),
+                                          &self.ciph.auth[..])?;
 
-            let mut chunk = c.1;
-            let mut work  = Vec::with_capacity(chunk.len());
-            work.write_all(chunk)
-                .expect("error reading chunk");
+        self.journaled(&mut map, &mut journal, Some(&hash_store))?;
 
-            let ic = c.0 as u64 * (1024*1024/64);
+        let macs: Vec<::KTag> = (0..aligned)
+            .map(|idx| hash_store.remove(&idx))
+            .collect::<Option<_>>()
+            .ok_or(::Error::InvalidLength)?;
 
-            println!("*fn encrypt:\n    thread {} using ic: {}\n",
-                c.0,
-                ic);
+        let seal = ::tag::seal(&self.ciph, &macs, l as u64);
+        let tmp  = ::KTag(*self.name_tag);
 
-            ::xcc::stream_xor_ic_inplace(&mut work[..],
-                                         &self.ciph.nons,
-                                         ic,
-                                         &self.ciph.keys);
+        ::tag::store(&self.path, &macs)?;
+
+        self.authenticated = Some(true);
 
-            //let c_time = t_timer.elapsed();
+        trace!("*fn encrypt:\n    file {} took {:#?} to encrypt and tag\n",
+            self.path,
+            timer.elapsed());
 
-            //t_timer = Instant::now();
+        // the journal outlives the seal update, so a crash before the
+        // keystore is written still leaves something to recover from
+        self.meta.update_entry_by_seal(&tmp[..],
+                                       &seal)?;
+        self.set_state(State::Encrypted)?;
 
-            let mut h
-                = Blake2b::with_key(64,
-                    &self.ciph.auth[..]);
+        journal.remove()
+    }
 
-            h.update(&work[..]);
+    // runs the journal's transform over the map a batch at a time. each
+    // batch is journaled and synced before the file is touched, so a
+    // crash leaves at most one partly written batch for recover to replay
+    fn journaled(&self,
+                 map: &mut ::MmapMut,
+                 journal: &mut Journal,
+                 hash_store: Option<&CHashMap<usize, ::KTag>>)
+      -> ::Result<()>
+    {
+        let chunk = journal.chunk as usize;
+        let batch = journal.batch as usize;
+        let op    = journal.op;
+        let len   = map.len();
 
-            let mut r = ::KTag([0u8; 64]);
-            r.clone_from_slice(h.finalize()
-                                .as_bytes());
+        let mut start = journal.from as usize;
 
-            hash_store.insert_new(c.0, r);
+        while start < journal.to as usize {
+            let end = ::std::cmp::min(start + batch, journal.to as usize);
 
-            chunk.write_all(&work[..])
-                .expect("could not write chunk");
+            let at   = start * chunk;
+            let upto = ::std::cmp::min(end * chunk, len);
 
-            /*println!("crypt #{} took: {:#?}\ntag #{} took: {:#?}",
-                chunk.0,
-                c_time,
-                chunk.0,
-                t_timer.elapsed());*/
-        });
+            // the batch as ciphertext, whichever way the file is going
+            let mut sealed = map[at..upto].to_vec();
 
-        let mut tag     = ::KTag([0u8; 64]);
-        let mut finaler = ::Keccak::new_keccak512();
+            if op == Op::Encrypt {
+                sealed.par_chunks_mut(chunk).enumerate().for_each(|c| {
+                    let idx = start + c.0;
+                    let ic  = ::cipher::ic(idx, chunk);
 
-        finaler.update(&self.ciph
-                            .afin[..]);
+                    trace!("*fn encrypt:\n    thread {} using ic: {}\n",
+                        idx,
+                        ic);
 
-        let mut idx = 0;
-        while idx < aligned { // probably off by one?
-            let e = hash_store.get(&idx);
+                    ::xcc::stream_xor_ic_inplace(c.1,
+                                                 &self.ciph.nons,
+                                                 ic,
+                                                 &self.ciph.keys);
 
-            if e.is_some() {
-                finaler.update(&e.unwrap()[..]);
-                idx += 1;
+                    if let Some(hash_store) = hash_store {
+                        hash_store.insert_new(idx, ::tag::chunk_mac(&self.ciph, c.1));
+                    }
+                });
             }
+
+            journal.record(start as u64, &sealed, &self.ciph.auth[..])?;
+
+            self.apply(map, start, chunk, &sealed, op);
+            map.flush_range(at, upto - at)?;
+
+            start = end;
         }
 
-        finaler.finalize(&mut *tag);
+        Ok(())
+    }
 
-        let tmp = ::KTag(self.name_tag
-                             .clone());
+    // writes a journaled batch back into the map, decrypting it on the
+    // way if the file is headed for plaintext. replaying it is harmless
+    fn apply(&self,
+             map: &mut [u8],
+             start: usize,
+             chunk: usize,
+             sealed: &[u8],
+             op: Op)
+    {
+        let at  = start * chunk;
+        let dst = &mut map[at..at + sealed.len()];
 
-        self.authenticated = Some(true);
+        dst.copy_from_slice(sealed);
 
-        println!("*fn encrypt:\n    file {} took {:#?} to encrypt and tag\n",
-            self.path,
-            timer.elapsed());
+        if op == Op::Decrypt {
+            dst.par_chunks_mut(chunk).enumerate().for_each(|c| {
+                let idx = start + c.0;
+                let ic  = ::cipher::ic(idx, chunk);
+
+                trace!("*fn decrypt:\n    thread {} using ic: {}\n",
+                    idx,
+                    ic);
 
-        Ok(self.meta.update_entry_by_tag(&tmp[..],
-                                         &*tag)?)
+                ::xcc::stream_xor_ic_inplace(c.1,
+                                             &self.ciph.nons,
+                                             ic,
+                                             &self.ciph.keys);
+            });
+        }
     }
 
-    pub fn authenticate(&mut self)
-      -> Result<Option<bool>, ::std::io::Error>
+    // finishes or undoes an encrypt or decrypt that was cut short,
+    // returning which one it was, or None if nothing was interrupted
+    pub fn recover(&mut self,
+                   how: Recovery)
+      -> ::Result<Option<Op>>
     {
-        let timer = Instant::now();
+        self.writable()?;
+
+        let tmp = ::KTag(*self.name_tag);
+
+        // a file that was never encrypted can't have been interrupted,
+        // but a journal needs the entry's keys to open
+        if self.meta.get_entry(&tmp[..])?.is_none() {
+            if Journal::exists(&self.path)
+            { return Err(::Error::EntryNotFound) }
+
+            return Ok(None)
+        }
+
+        let mut journal = match Journal::open(&self.path, &self.ciph.auth[..])? {
+            Some(j) => j,
+            None    => {
+                // entries go InProgress before their journal is written,
+                // so without one the file was never touched
+                if self.status()? == State::InProgress {
+                    let state = if self.authenticate()?.intact() { State::Encrypted } else { State::Plain };
+                    self.set_state(state)?;
+                }
+
+                return Ok(None)
+            },
+        };
 
-        println!("opening {}", &self.path);
         let f = OpenOptions::new()
             .write(true)
             .read(true)
             .open(&self.path)?;
 
-        let map = unsafe { ::MmapMut::map_mut(&f)? };
+        let mut map = unsafe { ::MmapMut::map_mut(&f)? };
 
-        let l       = map.len();
-        let aligned = ::cipher::align(l);
+        if map.len() as u64 != journal.len || journal.chunk == 0
+        { return Err(::Error::JournalCorrupt) }
 
-        println!("*fn authenticate:\n   map len: {}, supposed chunk count: {}\n",
-            l,
-            aligned);
+        // only the newest batch can be half written, replay it and
+        // everything up to its end is done
+        let done = match journal.latest(&self.ciph.auth[..])? {
+            Some(r) => {
+                if r.start < journal.from ||
+                   r.start + r.count > journal.to ||
+                   r.start * journal.chunk + r.data.len() as u64 > journal.len
+                { return Err(::Error::JournalCorrupt) }
 
-        let hash_store: CHashMap<usize, ::KTag>
-            = CHashMap::with_capacity(aligned);
+                self.apply(&mut map, r.start as usize, journal.chunk as usize, &r.data, journal.op);
+                map.flush()?;
 
-        map.par_chunks(1024*1024)
-           .enumerate()
-           .for_each(|chunk| {
-            let mut h
-                = Blake2b::with_key(64,
-                    &self.ciph.auth[..]);
+                r.start + r.count
+            },
+            None => journal.from,
+        };
 
-            h.update(&chunk.1[..]);
+        let (op, from, to) = match how {
+            Recovery::Finish   => (journal.op, done, journal.to),
+            Recovery::RollBack => (journal.op.inverse(), journal.from, done),
+        };
 
-            let mut r = ::KTag([0u8; 64]);
-            r.clone_from_slice(h.finalize()
-                                .as_bytes());
+        // replaces the old journal, so one always describes what's left
+        let mut next = Journal::create(&self.path,
+                                       op,
+                                       journal.len,
+                                       journal.chunk,
+                                       from,
+                                       to,
+                                       journal.batch,
+                                       &self.ciph.auth[..])?;
+
+        self.journaled(&mut map, &mut next, None)?;
+
+        if op == Op::Encrypt {
+            let macs = self.macs_of(&map, journal.chunk as usize);
+            let seal = ::tag::seal(&self.ciph, &macs, journal.len);
+            let tmp  = ::KTag(*self.name_tag);
+
+            ::tag::store(&self.path, &macs)?;
+
+            self.meta.update_entry_by_seal(&tmp[..],
+                                           &seal)?;
+            self.set_state(State::Encrypted)?;
+            self.authenticated = Some(true);
+        } else {
+            self.set_state(State::Plain)?;
+            ::tag::discard(&self.path)?;
+            self.authenticated = None;
+        }
 
-            //println!("chunk hash sample #{}: {:?}", chunk.0, &r[0..8]);
+        next.remove()?;
 
-            hash_store.insert_new(chunk.0, r);
-        });
+        Ok(Some(journal.op))
+    }
 
-        let mut found   = ::KTag([0u8; 64]);
-        let mut finaler = ::Keccak::new_keccak512();
+    // every chunk's mac over the whole file as it stands
+    fn macs_of(&self,
+               map: &[u8],
+               chunk: usize)
+      -> Vec<::KTag>
+    {
+        map.par_chunks(chunk)
+           .map(|c| ::tag::chunk_mac(&self.ciph, c))
+           .collect()
+    }
 
-        finaler.update(&self.ciph
-                            .afin[..]);
+    // checks the file against its entry's seal. when it doesn't match,
+    // the report says how and which chunks are to blame, as far as the
+    // sidecar can tell
+    pub fn authenticate(&mut self)
+      -> ::Result<Report>
+    {
+        let timer = Instant::now();
 
-        let mut idx = 0;
-        while idx < aligned { // probably off by one?
-            let e = hash_store.get(&idx);
+        self.load()?;
+        let chunk = self.meta.get_chunk();
+        let seal  = self.meta.get_seal();
 
-            if e.is_some() {
-                finaler.update(&e.unwrap()[..]);
-                idx += 1;
-            }
-        }
+        // only ever read, so read-only files and mounts can be checked
+        trace!("opening {}", &self.path);
+        let f = File::open(&self.path)?;
 
-        finaler.finalize(&mut *found);
+        let len  = f.metadata()?.len();
+        let macs = if len == 0 {
+            Vec::new()
+        } else {
+            let map = unsafe { ::MmapOptions::new().map(&f)? };
 
-        let result = ::memcmp(self.meta
-                                  .get_hmac(),
-                              &found[..]);
+            trace!("*fn authenticate:\n   map len: {}, supposed chunk count: {}\n",
+                map.len(),
+                ::cipher::align(map.len(), chunk));
+
+            self.macs_of(&map, chunk)
+        };
+
+        let fault = match ::tag::check(&self.ciph, &macs, len, &seal) {
+            Ok(())                          => None,
+            Err(e @ ::Error::FileTampered) |
+            Err(e @ ::Error::Truncated)    |
+            Err(e @ ::Error::Extended)     |
+            Err(e @ ::Error::Reordered)     => Some(e),
+            Err(e)                          => return Err(e),
+        };
 
-        self.authenticated = Some(result);
+        self.authenticated = Some(fault.is_none());
 
-        println!("*fn authenticate:\n   file {} authentication took: {:?}\n",
+        let stored = ::tag::stored(&self.path, &self.ciph, &seal)?;
+
+        let damaged = match (&fault, stored) {
+            (None, None) => {
+                // rebuilt while it's known good, a read-only directory is fine
+                ::tag::store(&self.path, &macs).ok();
+                Vec::new()
+            },
+            (None, _)         => Vec::new(),
+            (Some(_), stored) => {
+                let expect = if seal.bound { ::cipher::align(seal.len as usize, chunk) } else { macs.len() };
+
+                ::tag::locate(&macs, stored.as_deref(), expect)
+            },
+        };
+
+        trace!("*fn authenticate:\n   file {} authentication took: {:?}\n",
             self.path,
             timer.elapsed());
 
-        Ok(Some(result))
+        Ok(
+        Report {
+            chunk,
+            len: if seal.bound { seal.len } else { len },
+            fault,
+            damaged,
+        })
+    }
+
+    // authenticate, failing with Truncated, Extended, Reordered or
+    // FileTampered rather than reporting
+    pub fn verify(&mut self)
+      -> ::Result<()>
+    {
+        self.authenticate()?.into_result()
     }
 
     pub fn decrypt(&mut self)
-      -> Result<Option<bool>, ::std::io::Error>
+      -> ::Result<()>
     {
-        if self.authenticated.is_none() {
-            match self.authenticate()? {
-                Some(false) => return Ok(Some(false)),
-                None        => return Ok(None),
-                Some(true)  => (),
-            }
+        self.writable()?;
+
+        // a file this keystore has no entry for was never encrypted
+        // under it, or is being named by a path it wasn't encrypted at
+        self.load()?;
+
+        match self.status()? {
+            State::InProgress => return Err(::Error::Interrupted),
+            State::Plain      => return Err(::Error::NotEncrypted),
+            _                 => (),
+        }
+
+        if self.authenticated != Some(true) {
+            self.verify()?;
         }
-        if !self.authenticated.unwrap()
-        { return Ok(Some(false)) }
 
         let timer = Instant::now();
 
-        println!("decrypting {}",
+        trace!("decrypting {}",
             &self.path);
 
         let f = OpenOptions::new()
@@ -279,35 +755,360 @@ impl Crypt {
             .read(true)
             .open(&self.path)?;
 
+        if f.metadata()?.len() == 0 {
+            self.authenticated = None;
+
+            return self.set_state(State::Plain)
+        }
+
         let mut map = unsafe { ::MmapMut::map_mut(&f)? };
 
-        println!("*fn decrypt:\n   map len: {}\n",
+        trace!("*fn decrypt:\n   map len: {}\n",
             map.len());
 
-        map.par_chunks_mut(1024*1024).enumerate().for_each(|c| {
-            //let mut t_timer = Instant::now();
+        self.set_state(State::InProgress)?;
+
+        let chunk = self.meta.get_chunk();
+
+        let mut journal = Journal::create(&self.path,
+                                          Op::Decrypt,
+                                          map.len() as u64,
+                                          chunk as u64,
+                                          0,
+                                          ::cipher::align(map.len(), chunk) as u64,
+                                          batch_for(chunk),
+                                          &self.ciph.auth[..])?;
+
+        self.journaled(&mut map, &mut journal, None)?;
+
+        self.set_state(State::Plain)?;
+        ::tag::discard(&self.path)?;
+
+        journal.remove()?;
+
+        self.authenticated = None;
+
+        trace!("*fn decrypt:\n    file {} took {:#?} to decrypt\n",
+            self.path,
+            timer.elapsed());
+
+        Ok(())
+    }
+
+    // plaintext of len bytes at offset, checking and decrypting only the
+    // chunks they fall in. the file on disk is left alone
+    pub fn read_range(&mut self,
+                      offset: u64,
+                      len: usize)
+      -> ::Result<Vec<u8>>
+    {
+        self.load()?;
+
+        match self.status()? {
+            State::InProgress => return Err(::Error::Interrupted),
+            State::Plain      => return Err(::Error::NotEncrypted),
+            _                 => (),
+        }
+
+        let chunk = self.meta.get_chunk();
+        let seal  = self.meta.get_seal();
+
+        let f  = File::open(&self.path)?;
+        let fl = f.metadata()?.len();
+
+        if seal.bound && fl < seal.len
+        { return Err(::Error::Truncated) }
+        if seal.bound && fl > seal.len
+        { return Err(::Error::Extended) }
+
+        let end = offset.checked_add(len as u64)
+            .ok_or(::Error::InvalidLength)?;
+
+        if end > fl
+        { return Err(::Error::InvalidLength) }
+
+        if len == 0
+        { return Ok(Vec::new()) }
+
+        let map = unsafe { ::MmapOptions::new().map(&f)? };
+
+        // without a sidecar that matches the seal the whole file has to
+        // be checked once, which leaves one behind for next time
+        let macs = match ::tag::stored(&self.path, &self.ciph, &seal)? {
+            Some(m) => m,
+            None    => {
+                let m = self.macs_of(&map, chunk);
+                ::tag::check(&self.ciph, &m, fl, &seal)?;
+
+                // only saves work later, a read-only directory is fine
+                ::tag::store(&self.path, &m).ok();
+                m
+            },
+        };
 
-            let chunk = c.1;
+        let first = offset as usize / chunk;
+        let last  = (end as usize - 1) / chunk;
 
-            let ic = c.0 as u64 * (1024*1024/64);
-            println!("*fn decrypt:\n    thread {} using ic: {}\n",
-                c.0,
-                ic);
+        if last >= macs.len()
+        { return Err(::Error::FileTampered) }
 
-            ::xcc::stream_xor_ic_inplace(chunk,
+        let at   = first * chunk;
+        let upto = ::std::cmp::min((last + 1) * chunk, map.len());
+
+        let mut out = map[at..upto].to_vec();
+
+        for (i, c) in out.chunks(chunk).enumerate() {
+            if !::memcmp(&::tag::chunk_mac(&self.ciph, c)[..], &macs[first + i][..])
+            { return Err(::Error::FileTampered) }
+        }
+
+        for (i, c) in out.chunks_mut(chunk).enumerate() {
+            ::xcc::stream_xor_ic_inplace(c,
                                          &self.ciph.nons,
-                                         ic,
+                                         ::cipher::ic(first + i, chunk),
                                          &self.ciph.keys);
+        }
+
+        let skip  = offset as usize - at;
+        let plain = out[skip..skip + len].to_vec();
+        ::memzero(&mut out);
+
+        Ok(plain)
+    }
+
+    fn cipher(&self)
+      -> ::Result<Cipher>
+    {
+        let mut raw = [0u8; ::cipher::RAW_LEN];
+        self.ciph.write_raw(&mut raw);
+
+        let c = Cipher::from_vecs(&raw[0..56], &raw[56..]);
+        ::memzero(&mut raw);
+
+        c
+    }
+
+    // streams ciphertext for this file into out under new keys, leaving
+    // the entry InProgress until the writer is handed to finish_writer
+    pub fn encrypt_writer<W: Write>(&mut self,
+                                    out: W)
+      -> ::Result<EncryptWriter<W>>
+    {
+        self.rekey()?;
+
+        Ok(EncryptWriter::new(out, self.cipher()?, self.chunk))
+    }
+
+    // flushes the writer and records its seal, returning what it wrapped
+    pub fn finish_writer<W: Write>(&mut self,
+                                   writer: EncryptWriter<W>)
+      -> ::Result<W>
+    {
+        let (out, seal) = writer.finish()?;
+        let tmp = ::KTag(*self.name_tag);
+
+        self.meta.update_entry_by_seal(&tmp[..],
+                                       &seal)?;
+        self.set_state(State::Encrypted)?;
+        self.authenticated = None;
+
+        Ok(out)
+    }
+
+    // plaintext of this file's ciphertext read from src, only vouched
+    // for once the reader hits the end of the stream without error
+    pub fn decrypt_reader<R: Read>(&mut self,
+                                   src: R)
+      -> ::Result<DecryptReader<R>>
+    {
+        self.load()?;
+
+        match self.status()? {
+            State::InProgress => return Err(::Error::Interrupted),
+            State::Plain      => return Err(::Error::NotEncrypted),
+            _                 => (),
+        }
+
+        DecryptReader::new(src, self.cipher()?, self.meta.get_seal(), self.meta.get_chunk())
+    }
+
+    // encrypts this file into dest, which gets its own entry in this
+    // keystore, leaving the source as it was. only reads the source, so
+    // it can sit on read-only storage
+    pub fn encrypt_to(&mut self,
+                      dest: &str)
+      -> ::Result<()>
+    {
+        self.writable()?;
+
+        match self.status()? {
+            State::InProgress => return Err(::Error::Interrupted),
+            State::Encrypted  => return Err(::Error::AlreadyEncrypted),
+            _                 => (),
+        }
+
+        let dest_id   = identity(&self.meta.backing, dest)?;
+        let dest_name = self.meta.name_of(&dest_id);
 
-            /*println!("crypt #{} took: {:#?}",
-                chunk.0,
-                t_timer.elapsed());*/
+        let existing = self.meta.get_entry(&dest_name[..])?;
+        if existing.is_some() && self.meta.get_state() == State::InProgress
+        { return Err(::Error::Interrupted) }
+
+        let ciph = Cipher::random()?;
+
+        let mut raw = [0u8; ::cipher::RAW_LEN];
+        ciph.write_raw(&mut raw);
+
+        let mut src = File::open(&self.path)?;
+        let chunk   = self.chunk;
+
+        // the keys reach the keystore before dest is replaced, so a crash
+        // between the two leaves dest.tmp readable rather than orphaned
+        let written = write_through(dest, |out| {
+            let mut w = EncryptWriter::new(out, ciph, chunk);
+            ::std::io::copy(&mut src, &mut w)?;
+
+            Ok(w.finish()?.1)
+        }, |seal| {
+            if existing.is_some() {
+                return self.meta.rekey_entry(&dest_name[..],
+                                             &raw[0..56],
+                                             &raw[56..],
+                                             &seal,
+                                             chunk,
+                                             State::Encrypted)
+            }
+
+            if dest_id.len() > ::key_store::MAX_PATH_LEN
+            { return Err(::Error::PathTooLong) }
+
+            let mut ent = Entry::from_pieces(&dest_name[..], &raw[0..56], &raw[56..], &seal.tag, dest_id.as_bytes())
+                .ok_or(::Error::InvalidLength)?;
+
+            ent.update_seal(&seal);
+            ent.update_chunk(chunk);
+            ent.update_state(State::Encrypted);
+
+            self.meta.add_whole_entry(&ent)
         });
 
-        println!("*fn decrypt:\n    file {} took {:#?} to decrypt\n",
-            self.path,
-            timer.elapsed());
+        ::memzero(&mut raw);
+
+        written
+    }
+
+    // decrypts this file into dest, leaving the ciphertext and its entry
+    // as they were. dest only appears once the whole file authenticated
+    pub fn decrypt_to(&mut self,
+                      dest: &str)
+      -> ::Result<()>
+    {
+        let mut reader = self.decrypt_reader(File::open(&self.path)?)?;
+
+        write_through(dest, |out| {
+            ::std::io::copy(&mut reader, out)?;
+            Ok(())
+        }, Ok)
+    }
+
+    // moves the file to `to` and its entry along with it. the entry moves
+    // first and is moved back if the file can't follow
+    pub fn rename(&mut self,
+                  to: &str)
+      -> ::Result<()>
+    {
+        self.writable()?;
+        self.load()?;
+
+        if Journal::exists(&self.path)
+        { return Err(::Error::Interrupted) }
+
+        if ::std::fs::symlink_metadata(to).is_ok()
+        { return Err(::Error::Io(::std::io::Error::from(::std::io::ErrorKind::AlreadyExists))) }
+
+        let to_id = identity(&self.meta.backing, to)?;
+        let tmp   = ::KTag(*self.name_tag);
+
+        self.meta.rebind_entry(&tmp[..], &to_id)?;
+        let to_name = self.meta.name_of(&to_id);
+
+        if let Err(e) = ::std::fs::rename(&self.path, to) {
+            self.meta.rebind_entry(&to_name[..], &self.id)?;
+            return Err(::Error::Io(e))
+        }
+
+        // a sidecar left behind only costs the next check a full read,
+        // authenticate writes a new one
+        let _ = ::std::fs::rename(::tag::sidecar_for(&self.path), ::tag::sidecar_for(to));
+
+        self.path     = String::from(to);
+        self.id       = to_id;
+        self.name_tag = to_name;
+
+        Ok(())
+    }
+
+    // for a file moved outside the tool: finds the entry whose seal the
+    // file matches and rebinds it here, returning the path it was recorded
+    // under. only entries whose own file is gone are candidates, so copies
+    // don't take over the original's entry. empty files carry too little
+    // to tell entries apart and are never reattached
+    pub fn reattach(&mut self)
+      -> ::Result<Option<String>>
+    {
+        self.writable()?;
+
+        let tmp = ::KTag(*self.name_tag);
+
+        if self.meta.get_entry(&tmp[..])?.is_some()
+        { return Err(::Error::EntryExists) }
+
+        let f   = File::open(&self.path)?;
+        let len = f.metadata()?.len();
+
+        if len == 0
+        { return Ok(None) }
+
+        let map  = unsafe { ::MmapOptions::new().map(&f)? };
+        let root = canonical(Path::new(&self.meta.backing))?;
+        let root = root.parent().unwrap_or_else(|| Path::new("/"));
+
+        for e in self.meta.entries()? {
+            match e.state() {
+                State::Encrypted | State::Unknown => (),
+                _                                 => continue,
+            }
+
+            let seal = e.seal();
+            if seal.bound && seal.len != len
+            { continue }
+
+            let recorded = String::from_utf8_lossy(e.path()).into_owned();
+            if !recorded.is_empty() && root.join(&recorded).exists()
+            { continue }
+
+            let ciph = Cipher::from_vecs(e.crypt(), e.auth())?;
+            let macs: Vec<::KTag> = map.par_chunks(e.chunk())
+                .map(|c| ::tag::chunk_mac(&ciph, c))
+                .collect();
+
+            if ::tag::check(&ciph, &macs, len, &seal).is_err()
+            { continue }
+
+            self.meta.rebind_entry(e.name(), &self.id)?;
+
+            self.name_tag = self.meta.name_of(&self.id);
+            self.load()?;
+
+            self.ciph          = ciph;
+            self.chunk         = e.chunk();
+            self.authenticated = Some(true);
+
+            ::tag::store(&self.path, &macs)?;
+
+            return Ok(Some(recorded))
+        }
 
-        Ok(Some(true))
+        Ok(None)
     }
 }
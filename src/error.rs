@@ -0,0 +1,126 @@
+/// error type shared by the cipher, keystore and crypt modules
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    // underlying file or mmap failure
+    Io(::std::io::Error),
+    // argon2 rejected its input or parameters
+    Kdf(::argon2::Error),
+    // keystore check value did not match the derived keys
+    WrongPassword,
+    // passwords must be at least secret::MIN_PASSWORD bytes
+    PasswordTooShort,
+    // keystore is truncated or its hmac does not match its contents
+    KeystoreTampered,
+    // keystore predates the versioned format and needs migrating
+    LegacyKeystore,
+    // keystore was written by a newer or unknown format version
+    UnsupportedVersion(u32),
+    // file contents do not match the tag stored in the keystore
+    FileTampered,
+    // no keystore entry exists for the requested name
+    EntryNotFound,
+    // argon2 costs are zero or past cipher::MAX_* bounds
+    InvalidKdfParams,
+    // a key, salt, hash or tag had the wrong size
+    InvalidLength,
+    // path is longer than a keystore entry can record
+    PathTooLong,
+    // an encrypt or decrypt was cut short, leaving a journal or an
+    // entry still marked in progress
+    Interrupted,
+    // journal header failed authentication or describes another file
+    JournalCorrupt,
+    // file is already encrypted under its keystore entry
+    AlreadyEncrypted,
+    // file is recorded as plaintext, there is nothing to decrypt
+    NotEncrypted,
+    // chunk sizes must be a multiple of 64 between MIN_CHUNK and MAX_CHUNK
+    InvalidChunkSize,
+    // file is shorter than when it was encrypted
+    Truncated,
+    // file is longer than when it was encrypted
+    Extended,
+    // file holds the chunks it was encrypted to, out of order
+    Reordered,
+    // a keystore entry failed authentication on its own
+    EntryCorrupt,
+    // another keystore entry already has the name being moved to
+    EntryExists,
+    // keystore opened with corrupt entries and an hmac that doesn't match,
+    // it takes no writes until compacted
+    KeystoreDegraded,
+    // keystore was created with a keyfile and none was given
+    KeyfileRequired,
+    // a keyfile was given for a keystore that doesn't use one
+    UnexpectedKeyfile,
+    // the keystore a command reads isn't there, named by its path
+    NoKeystore(String),
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e)        => write!(f, "io error: {}", e),
+            Error::Kdf(ref e)       => write!(f, "kdf error: {}", e),
+            Error::WrongPassword    => write!(f, "wrong password"),
+            Error::PasswordTooShort => write!(f, "password must be at least 16 bytes"),
+            Error::KeystoreTampered => write!(f, "keystore failed authentication"),
+            Error::LegacyKeystore   => write!(f, "keystore uses the legacy format, migrate it first"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported keystore format version {}", v),
+            Error::FileTampered     => write!(f, "file failed authentication"),
+            Error::EntryNotFound    => write!(f, "no keystore entry for file"),
+            Error::InvalidKdfParams => write!(f, "argon2 parameters are out of range"),
+            Error::InvalidLength    => write!(f, "invalid key, salt or tag length"),
+            Error::PathTooLong      => write!(f, "path is too long for a keystore entry"),
+            Error::Interrupted      => write!(f, "an encrypt or decrypt of the file was interrupted, recover it first"),
+            Error::JournalCorrupt   => write!(f, "journal failed authentication"),
+            Error::AlreadyEncrypted => write!(f, "file is already encrypted"),
+            Error::NotEncrypted     => write!(f, "file is not encrypted"),
+            Error::InvalidChunkSize => write!(f, "chunk size must be a multiple of 64 from 64 bytes to 1 GiB"),
+            Error::Truncated        => write!(f, "file was truncated"),
+            Error::Extended         => write!(f, "file was extended"),
+            Error::Reordered        => write!(f, "file chunks were reordered"),
+            Error::EntryCorrupt     => write!(f, "keystore entry failed authentication"),
+            Error::EntryExists      => write!(f, "a keystore entry already exists for that path"),
+            Error::KeystoreDegraded => write!(f, "keystore has corrupt entries and is read-only until compacted"),
+            Error::KeyfileRequired  => write!(f, "keystore requires a keyfile"),
+            Error::UnexpectedKeyfile => write!(f, "keystore doesn't use a keyfile"),
+            Error::NoKeystore(ref p) => write!(f, "no keystore at {}", p),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn (::std::error::Error) + 'static)> {
+        match *self {
+            Error::Io(ref e)  => Some(e),
+            Error::Kdf(ref e) => Some(e),
+            _                 => None,
+        }
+    }
+}
+
+impl From<::std::io::Error> for Error {
+    fn from(e: ::std::io::Error) -> Error {
+        // the stream adapters carry our own errors through io::Error
+        if !e.get_ref().is_some_and(|i| i.is::<Error>())
+        { return Error::Io(e) }
+
+        let kind = e.kind();
+
+        e.into_inner()
+            .and_then(|i| i.downcast::<Error>().ok())
+            .map(|inner| *inner)
+            .unwrap_or_else(|| Error::Io(::std::io::Error::from(kind)))
+    }
+}
+
+impl From<::argon2::Error> for Error {
+    fn from(e: ::argon2::Error) -> Error {
+        Error::Kdf(e)
+    }
+}
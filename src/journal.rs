@@ -0,0 +1,288 @@
+/// write-ahead journal that makes in-place chunk transforms
+/// resumable, kept in a sidecar next to the file being transformed
+use blake2_rfc::blake2b::Blake2b as Blake2b;
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use ::Error as Error;
+
+pub const MAGIC: &[u8; 4] = b"SMJL";
+
+// magic 4 | op 4 | len 8 | chunk 8 | from 8 | to 8 | batch 8 | mac 64
+const HEADER_LEN: u64 = 112;
+
+// seq 8 | start 8 | count 8 | data len 8, followed by data and a 64 byte mac
+const RECORD_LEN: u64 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Encrypt = 1,
+    Decrypt = 2,
+}
+
+impl Op {
+    pub fn inverse(self) -> Op {
+        match self {
+            Op::Encrypt => Op::Decrypt,
+            Op::Decrypt => Op::Encrypt,
+        }
+    }
+
+    fn from_u32(v: u32) -> Option<Op> {
+        match v {
+            1 => Some(Op::Encrypt),
+            2 => Some(Op::Decrypt),
+            _ => None,
+        }
+    }
+}
+
+// a batch as written to the journal: chunks start..start+count,
+// always in their encrypted form so the journal never holds plaintext
+pub struct Record {
+    pub start: u64,
+    pub count: u64,
+    pub data: Vec<u8>,
+}
+
+// records alternate between two slots, so the newest valid one is the
+// only batch that might be partly applied, and everything before it is
+// done. a torn write can only ever damage the newer slot
+pub struct Journal {
+    file: File,
+    path: String,
+    mac: ::KTag,
+    seq: u64,
+    pub op: Op,
+    pub len: u64,
+    pub chunk: u64,
+    pub from: u64,
+    pub to: u64,
+    pub batch: u64,
+}
+
+fn word(raw: &[u8], at: usize) -> u64 {
+    let mut w = [0u8; 8];
+    w.clone_from_slice(&raw[at..at+8]);
+    u64::from_le_bytes(w)
+}
+
+fn keyed(auth: &[u8], parts: &[&[u8]]) -> ::KTag {
+    let mut h = Blake2b::with_key(64, auth);
+    for p in parts {
+        h.update(p);
+    }
+
+    let mut r = ::KTag([0u8; 64]);
+    r.clone_from_slice(h.finalize().as_bytes());
+    r
+}
+
+impl Journal {
+    pub fn path_for(target: &str) -> String {
+        String::from(target) + ".journal"
+    }
+
+    pub fn exists(target: &str) -> bool {
+        ::std::fs::metadata(Journal::path_for(target)).is_ok()
+    }
+
+    // atomically replaces any journal for target, so there is always
+    // exactly one journal describing the work left to do
+    #[allow(clippy::too_many_arguments)]
+    pub fn create(target: &str,
+                  op: Op,
+                  len: u64,
+                  chunk: u64,
+                  from: u64,
+                  to: u64,
+                  batch: u64,
+                  auth: &[u8])
+      -> ::Result<Journal>
+    {
+        let mut h = [0u8; 48];
+
+        h[0..4].clone_from_slice(MAGIC);
+        h[4..8].clone_from_slice(&(op as u32).to_le_bytes());
+        h[8..16].clone_from_slice(&len.to_le_bytes());
+        h[16..24].clone_from_slice(&chunk.to_le_bytes());
+        h[24..32].clone_from_slice(&from.to_le_bytes());
+        h[32..40].clone_from_slice(&to.to_le_bytes());
+        h[40..48].clone_from_slice(&batch.to_le_bytes());
+
+        let mac  = keyed(auth, &[&h[..]]);
+        let path = Journal::path_for(target);
+
+        ::key_store::write_atomic(&path, &[&h[..], &mac[..]])?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+
+        Ok(
+        Journal {
+            file,
+            path,
+            mac,
+            seq: 0,
+            op,
+            len,
+            chunk,
+            from,
+            to,
+            batch,
+        })
+    }
+
+    pub fn open(target: &str,
+                auth: &[u8])
+      -> ::Result<Option<Journal>>
+    {
+        let path = Journal::path_for(target);
+
+        let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(f)  => f,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound
+                   => return Ok(None),
+            Err(e) => return Err(Error::Io(e)),
+        };
+
+        let mut h = [0u8; HEADER_LEN as usize];
+        if file.read_exact(&mut h).is_err()
+        { return Err(Error::JournalCorrupt) }
+
+        let mac = keyed(auth, &[&h[0..48]]);
+
+        if &h[0..4] != MAGIC || !::memcmp(&h[48..112], &mac[..])
+        { return Err(Error::JournalCorrupt) }
+
+        let mut o = [0u8; 4];
+        o.clone_from_slice(&h[4..8]);
+
+        let op = Op::from_u32(u32::from_le_bytes(o))
+            .ok_or(Error::JournalCorrupt)?;
+
+        let mut j = Journal {
+            file,
+            path,
+            mac,
+            seq: 0,
+            op,
+            len: word(&h, 8),
+            chunk: word(&h, 16),
+            from: word(&h, 24),
+            to: word(&h, 32),
+            batch: word(&h, 40),
+        };
+
+        if let Some(r) = j.read_slot(0, auth)?.into_iter()
+            .chain(j.read_slot(1, auth)?)
+            .max_by_key(|r| r.0)
+        {
+            j.seq = r.0 + 1;
+        }
+
+        Ok(Some(j))
+    }
+
+    #[inline]
+    fn slot_len(&self) -> u64 {
+        RECORD_LEN + self.batch * self.chunk + 64
+    }
+
+    fn read_slot(&mut self,
+                 slot: u64,
+                 auth: &[u8])
+      -> ::Result<Option<(u64, Record)>>
+    {
+        let at = HEADER_LEN + slot * self.slot_len();
+
+        if self.file.metadata()?.len() < at + RECORD_LEN
+        { return Ok(None) }
+
+        let mut r = [0u8; RECORD_LEN as usize];
+        self.file.seek(SeekFrom::Start(at))?;
+        self.file.read_exact(&mut r)?;
+
+        let seq   = word(&r, 0);
+        let start = word(&r, 8);
+        let count = word(&r, 16);
+        let dlen  = word(&r, 24);
+
+        // a torn or stale slot fails one of these, or its mac below
+        if count == 0 || count > self.batch || dlen > count * self.chunk
+        { return Ok(None) }
+
+        let mut data = vec![0u8; dlen as usize];
+        let mut mac  = [0u8; 64];
+
+        if self.file.read_exact(&mut data).is_err() ||
+           self.file.read_exact(&mut mac).is_err()
+        { return Ok(None) }
+
+        let rec = Record { start, count, data };
+
+        if !::memcmp(&mac, &keyed(auth, &[&self.mac[..], &r, &rec.data])[..])
+        { return Ok(None) }
+
+        Ok(Some((seq, rec)))
+    }
+
+    // newest intact record, the only one that may be partly applied
+    pub fn latest(&mut self,
+                  auth: &[u8])
+      -> ::Result<Option<Record>>
+    {
+        let a = self.read_slot(0, auth)?;
+        let b = self.read_slot(1, auth)?;
+
+        Ok(a.into_iter()
+            .chain(b)
+            .max_by_key(|r| r.0)
+            .map(|r| r.1))
+    }
+
+    // durably journals a batch of encrypted chunks, starting at chunk
+    // start, before any of them are written back to the file
+    pub fn record(&mut self,
+                  start: u64,
+                  data: &[u8],
+                  auth: &[u8])
+      -> ::Result<()>
+    {
+        let dlen  = data.len() as u64;
+        let count = dlen.div_ceil(self.chunk);
+
+        if count == 0 || count > self.batch
+        { return Err(Error::InvalidLength) }
+
+        let mut r = [0u8; RECORD_LEN as usize];
+
+        r[0..8].clone_from_slice(&self.seq.to_le_bytes());
+        r[8..16].clone_from_slice(&start.to_le_bytes());
+        r[16..24].clone_from_slice(&count.to_le_bytes());
+        r[24..32].clone_from_slice(&dlen.to_le_bytes());
+
+        let mac = keyed(auth, &[&self.mac[..], &r, data]);
+
+        let at = HEADER_LEN + (self.seq % 2) * self.slot_len();
+        self.file.seek(SeekFrom::Start(at))?;
+        self.file.write_all(&r)?;
+        self.file.write_all(data)?;
+        self.file.write_all(&mac[..])?;
+        self.file.sync_data()?;
+
+        self.seq += 1;
+
+        Ok(())
+    }
+
+    pub fn remove(self)
+      -> ::Result<()>
+    {
+        ::std::fs::remove_file(&self.path)?;
+
+        Ok(())
+    }
+}
@@ -1,49 +1,235 @@
 /// this module defines a file structure and associated
 /// functions for querying an encrypted key/value store
 /// as well as assuring its own authenticity
-use std::fs::OpenOptions;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::io::{Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Mutex, MutexGuard};
+use rust_sodium_sys::{crypto_aead_xchacha20poly1305_ietf_decrypt,
+                      crypto_aead_xchacha20poly1305_ietf_encrypt};
 use ::cipher::Cipher as Cipher;
+use ::cipher::KdfParams as KdfParams;
+use ::cipher::Keyfile as Keyfile;
+use ::tag::Seal as Seal;
+use ::Error as Error;
+
+// every versioned keystore starts with MAGIC followed by
+// its format version as a little endian u32
+pub const MAGIC: &[u8; 4] = b"SMKS";
+pub const FORMAT_VERSION: u32 = 8;
+
+// size of the plaintext header preceding the entries
+pub const HEADER_LEN: u64 = 280;
+
+// each entry is ENTRY_LEN bytes of plaintext
+pub const ENTRY_LEN: u64 = 1280;
+
+// and is stored sealed in a SLOT_LEN byte slot:
+// nonce 24 | name hash 64 | xchacha20-poly1305 ciphertext 1216 | mac 16
+// the name hash stays in the clear and goes into the ad, so a slot
+// can't be passed off as another entry's. up to v6 the whole entry was
+// sealed: nonce 24 | ciphertext 512 | mac 16
+pub const SLOT_LEN: u64 = 1320;
+
+// v3 to v7 entries, sealed from v5 on in SHORT_SLOT_LEN byte slots
+pub const SHORT_ENTRY_LEN: u64 = 512;
+pub const SHORT_SLOT_LEN: u64 = 552;
+
+// first format version whose slots carry their name in the clear
+pub const NAMED_SLOTS: u32 = 7;
+
+// first format version whose entries hold a path up to MAX_PATH_LEN
+pub const LONG_PATHS: u32 = 8;
+
+// header flag: the password key also needs a keyfile
+pub const FLAG_KEYFILE: u32 = 1;
+
+// longest path an entry can record, in bytes. PATH_MAX on macOS and
+// the BSDs, room for the absolute paths a central keystore names files
+// by without every slot paying for linux's 4096
+pub const MAX_PATH_LEN: usize = 1024;
+
+// what a v3 to v7 entry could record
+pub const SHORT_PATH_LEN: usize = 256;
+
+// the unversioned format: csalt, asalt and hmac, then 160 byte entries
+pub const LEGACY_HEADER_LEN: u64 = 96;
+
+// keystore files are read and written by one handle at a time: within a
+// process through FILE_LOCK, so handles from share can work on one file
+// from many threads, and across processes through an flock on the lock
+// file beside it, which write_atomic's renames leave alone. public methods
+// hold both from reading the keystore to the last write, the private ones
+// they call assume they're held
+static FILE_LOCK: Mutex<()> = Mutex::new(());
+
+// the flock goes with the file, before the mutex is let go
+pub struct Held {
+    _file: Option<File>,
+    _guard: MutexGuard<'static, ()>,
+}
+
+pub fn lock_path(path: &str)
+  -> String
+{
+    String::from(path) + ".lock"
+}
+
+pub fn locked(path: &str)
+  -> ::Result<Held>
+{
+    let guard = FILE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let lp    = lock_path(path);
+
+    // where the lock can't be made the keystore can't be written either,
+    // readers go ahead without it
+    let made = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lp);
+
+    let file = match made {
+        Ok(f)  => Some(f),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::PermissionDenied
+                   || e.raw_os_error() == Some(::libc::EROFS)
+               => File::open(&lp).ok(),
+        Err(e) => return Err(Error::Io(e)),
+    };
+
+    if let Some(ref f) = file {
+        while unsafe { ::libc::flock(f.as_raw_fd(), ::libc::LOCK_EX) } != 0 {
+            let e = ::std::io::Error::last_os_error();
+            if e.kind() != ::std::io::ErrorKind::Interrupted
+            { return Err(Error::Io(e)) }
+        }
+    }
+
+    Ok(Held { _file: file, _guard: guard })
+}
 
-pub struct Header(pub [u8; 96]);
+// the salts, check and params belong to the password-derived key, which
+// only wraps the master key that encrypts and authenticates the entries
+pub struct Header(pub [u8; 280]);
 
 impl Header {
+    #[inline]
+    pub fn magic(&self) -> &[u8] {
+        &self.0[0..4]
+    }
+
+    #[inline]
+    pub fn version(&self) -> u32 {
+        let mut v = [0u8; 4];
+        v.clone_from_slice(&self.0[4..8]);
+        u32::from_le_bytes(v)
+    }
+
     #[inline]
     pub fn csalt(&self) -> &[u8] {
-        &self.0[0..16]
+        &self.0[8..24]
     }
 
     #[inline]
     pub fn asalt(&self) -> &[u8] {
-        &self.0[16..32]
+        &self.0[24..40]
     }
 
     #[inline]
     pub fn hmac(&self) -> &[u8] {
-        &self.0[32..96]
+        &self.0[40..104]
+    }
+
+    // keccak of the password-derived auth keys, used to tell
+    // a wrong password apart from a tampered keystore
+    #[inline]
+    pub fn check(&self) -> &[u8] {
+        &self.0[104..168]
+    }
+
+    #[inline]
+    pub fn params(&self) -> Option<KdfParams> {
+        KdfParams::from_bytes(&self.0[168..180])
+    }
+
+    // bumped whenever the entries are rewritten, so other keystores
+    // open on the same file know their index is out of date
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        let mut b = [0u8; 8];
+        b.clone_from_slice(&self.0[180..188]);
+        u64::from_le_bytes(b)
+    }
+
+    // master key material encrypted under the password-derived key
+    #[inline]
+    pub fn wrapped(&self) -> &[u8] {
+        &self.0[188..276]
+    }
+
+    #[inline]
+    pub fn flags(&self) -> u32 {
+        let mut v = [0u8; 4];
+        v.clone_from_slice(&self.0[276..280]);
+        u32::from_le_bytes(v)
+    }
+
+    #[inline]
+    pub fn keyfile_required(&self) -> bool {
+        self.flags() & FLAG_KEYFILE != 0
+    }
+
+    pub fn set_flags(&mut self, flags: u32) {
+        self.0[276..280].clone_from_slice(&flags.to_le_bytes());
     }
 
     pub fn from_pieces(csalt: &[u8],
                        asalt: &[u8],
-                       hmac: &[u8])
+                       hmac: &[u8],
+                       check: &[u8],
+                       params: &KdfParams,
+                       generation: u64,
+                       wrapped: &[u8])
       -> Option<Header>
     {
-        if csalt.len() != 16 { return None }
-        if asalt.len() != 16 { return None }
-        if hmac.len()  != 64 { return None }
-
-        let mut h = [0u8; 96];
-
-        h[0..16].clone_from_slice(csalt);
-        h[16..32].clone_from_slice(asalt);
-        h[32..96].clone_from_slice(hmac);
+        if csalt.len()   != 16 { return None }
+        if asalt.len()   != 16 { return None }
+        if hmac.len()    != 64 { return None }
+        if check.len()   != 64 { return None }
+        if wrapped.len() != ::cipher::RAW_LEN { return None }
+
+        let mut h = [0u8; 280];
+
+        h[0..4].clone_from_slice(MAGIC);
+        h[4..8].clone_from_slice(&FORMAT_VERSION.to_le_bytes());
+        h[8..24].clone_from_slice(csalt);
+        h[24..40].clone_from_slice(asalt);
+        h[40..104].clone_from_slice(hmac);
+        h[104..168].clone_from_slice(check);
+        h[168..180].clone_from_slice(&params.to_bytes());
+        h[180..188].clone_from_slice(&generation.to_le_bytes());
+        h[188..276].clone_from_slice(wrapped);
 
         Some(Header(h))
     }
 }
 
-pub struct Entry(pub [u8; 160]);
+// where a file stands, Unknown being entries written before it was tracked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    Unknown    = 0,
+    Plain      = 1,
+    Encrypted  = 2,
+    InProgress = 3,
+}
+
+// name 64 | crypt key 56 | auth key 32 | hmac 64 | state 1 | chunk 4 | bound 1 | len 8
+// | order 16 | zero 8 | path len 2 | path 1024
+// up to v7 the path came before the state and had 256 bytes
+pub struct Entry(pub [u8; ENTRY_LEN as usize]);
 
 impl Entry {
     #[inline]
@@ -51,40 +237,133 @@ impl Entry {
         &self.0[0..64]
     }
 
+    // the file's xchacha20 key and nonce
     #[inline]
     pub fn crypt(&self) -> &[u8] {
-        &self.0[64..80]
+        &self.0[64..120]
     }
 
+    // the file's blake2b chunk key and keccak final key
     #[inline]
     pub fn auth(&self) -> &[u8] {
-        &self.0[80..96]
+        &self.0[120..152]
     }
 
     #[inline]
     pub fn hmac(&self) -> &[u8] {
-        &self.0[96..160]
+        &self.0[152..216]
     }
 
-    fn from_pieces(name_hash: &[u8],
-                       csalt: &[u8],
-                       asalt: &[u8],
-                       file_hash: &[u8])
+    // the path the entry was created for, empty for entries
+    // migrated from formats that didn't record one
+    #[inline]
+    pub fn path(&self) -> &[u8] {
+        let l = (u16::from_le_bytes([self.0[254], self.0[255]]) as usize)
+            .min(MAX_PATH_LEN);
+
+        &self.0[256..256 + l]
+    }
+
+    #[inline]
+    pub fn state(&self) -> State {
+        match self.0[216] {
+            1 => State::Plain,
+            2 => State::Encrypted,
+            3 => State::InProgress,
+            _ => State::Unknown,
+        }
+    }
+
+    // chunk size the file was encrypted with, 0 on disk for the default
+    #[inline]
+    pub fn chunk(&self) -> usize {
+        let mut w = [0u8; 4];
+        w.clone_from_slice(&self.0[217..221]);
+
+        match u32::from_le_bytes(w) {
+            0 => ::cipher::DEFAULT_CHUNK,
+            c => c as usize,
+        }
+    }
+
+    pub fn from_pieces(name_hash: &[u8],
+                       ckey: &[u8],
+                       akey: &[u8],
+                       file_hash: &[u8],
+                       path: &[u8])
       -> Option<Entry>
     {
         if name_hash.len() != 64 { return None }
-        if csalt.len()     != 16 { return None }
-        if asalt.len()     != 16 { return None }
+        if ckey.len()      != 56 { return None }
+        if akey.len()      != 32 { return None }
         if file_hash.len() != 64 { return None }
+        if path.len() > MAX_PATH_LEN { return None }
 
-        let mut e = [0u8; 160];
+        let mut e = Entry([0u8; ENTRY_LEN as usize]);
 
         e[0..64].clone_from_slice(name_hash);
-        e[64..80].clone_from_slice(csalt);
-        e[80..96].clone_from_slice(asalt);
-        e[96..160].clone_from_slice(file_hash);
+        e[64..120].clone_from_slice(ckey);
+        e[120..152].clone_from_slice(akey);
+        e[152..216].clone_from_slice(file_hash);
+        e[254..256].clone_from_slice(&(path.len() as u16).to_le_bytes());
+        e[256..256 + path.len()].clone_from_slice(path);
+
+        Some(e)
+    }
+
+    // a v3 to v7 entry in the current layout
+    pub fn from_short(old: &[u8])
+      -> Option<Entry>
+    {
+        if old.len() != SHORT_ENTRY_LEN as usize { return None }
+
+        let mut e = Entry([0u8; ENTRY_LEN as usize]);
+
+        e[0..216].clone_from_slice(&old[0..216]);
+        e[216..254].clone_from_slice(&old[474..512]);
+        e[254..512].clone_from_slice(&old[216..474]);
+
+        Some(e)
+    }
+
+    // the entry in the v3 to v7 layout, if its path fits
+    fn to_short(&self)
+      -> Option<[u8; SHORT_ENTRY_LEN as usize]>
+    {
+        if self.path().len() > SHORT_PATH_LEN { return None }
+
+        let mut old = [0u8; SHORT_ENTRY_LEN as usize];
+
+        old[0..216].clone_from_slice(&self.0[0..216]);
+        old[474..512].clone_from_slice(&self.0[216..254]);
+        old[216..474].clone_from_slice(&self.0[254..512]);
+
+        Some(old)
+    }
 
-        Some(Entry(e))
+    pub fn update_name(&mut self,
+                       name_hash: &[u8])
+      -> bool
+    {
+        if name_hash.len() != 64 { return false }
+
+        self[0..64].clone_from_slice(name_hash);
+
+        true
+    }
+
+    pub fn update_path(&mut self,
+                       path: &[u8])
+      -> bool
+    {
+        if path.len() > MAX_PATH_LEN { return false }
+
+        for b in self[254..].iter_mut() { *b = 0 }
+
+        self[254..256].clone_from_slice(&(path.len() as u16).to_le_bytes());
+        self[256..256 + path.len()].clone_from_slice(path);
+
+        true
     }
 
     pub fn update_tag(&mut self,
@@ -93,18 +372,81 @@ impl Entry {
     {
         if tag.len() != 64 { return false }
 
-        self[96..160].clone_from_slice(&tag[..]);
+        self[152..216].clone_from_slice(tag);
+
+        true
+    }
+
+    // the hmac with the length and order digest it was bound to, if any
+    pub fn seal(&self) -> Seal {
+        let mut s = Seal::empty();
+
+        let mut w = [0u8; 8];
+        w.clone_from_slice(&self.0[222..230]);
+
+        s.tag.clone_from_slice(self.hmac());
+        s.bound = self.0[221] == 1;
+        s.len   = u64::from_le_bytes(w);
+        s.order.clone_from_slice(&self.0[230..246]);
+
+        s
+    }
+
+    pub fn update_seal(&mut self,
+                       s: &Seal)
+    {
+        self[152..216].clone_from_slice(&s.tag);
+        self[221] = s.bound as u8;
+        self[222..230].clone_from_slice(&s.len.to_le_bytes());
+        self[230..246].clone_from_slice(&s.order);
+    }
+
+    pub fn update_keys(&mut self,
+                       ckey: &[u8],
+                       akey: &[u8])
+      -> bool
+    {
+        if ckey.len() != 56 || akey.len() != 32 { return false }
+
+        self[64..120].clone_from_slice(ckey);
+        self[120..152].clone_from_slice(akey);
+
+        true
+    }
+
+    pub fn update_state(&mut self,
+                        state: State)
+    {
+        self[216] = state as u8;
+    }
+
+    pub fn update_chunk(&mut self,
+                        chunk: usize)
+      -> bool
+    {
+        if ::cipher::check_chunk(chunk).is_err() { return false }
+
+        self[217..221].clone_from_slice(&(chunk as u32).to_le_bytes());
 
         true
     }
 }
 
-// change io methods' signatures to fn() -> Result<Option<_>, ::std::io::Error>
+// index maps each name hash to the first slot holding it, as of the
+// generation and slot count it was built at. slots that failed
+// authentication are in neither, only in corrupt. a keystore whose
+// hmac didn't match is degraded: readable, but nothing is written to
+// it until compact has dropped what doesn't authenticate
 pub struct KeyStore {
     pub current: Entry,
     pub key: Cipher,
+    pub params: KdfParams,
+    pub generation: u64,
     pub backing: String,
-    pub authenticated: bool,
+    index: HashMap<Vec<u8>, u64>,
+    indexed: u64,
+    corrupt: Vec<u64>,
+    degraded: bool,
 }
 
 impl Drop for KeyStore {
@@ -122,323 +464,882 @@ impl KeyStore {
         &self.key.afin
     }
 
-    fn create_from(pass: &str,
-                   path: &str)
-      -> Result<Option<KeyStore>, ::std::io::Error>
+    // another handle on the same file under the same master key,
+    // without going through the password again
+    pub fn share(&self)
+      -> ::Result<KeyStore>
     {
-        let mut f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(path)?;
-
-        let csalt = ::Salt::from_slice(&mut ::random(16))
-            .expect("rng error");
-        let asalt = ::Salt::from_slice(&mut ::random(16))
-            .expect("rng error");
-
-        let c = Cipher::from_argon(pass,
-                                   &*csalt,
-                                   &*asalt,
-                                   64*1024) // change for actual use
-            .expect("kdf error");
+        let mut raw = [0u8; ::cipher::RAW_LEN];
+        self.key.write_raw(&mut raw);
+
+        let key = Cipher::from_vecs(&raw[0..56], &raw[56..]);
+        ::memzero(&mut raw);
+
+        Ok(KeyStore {
+            current: Entry([0u8; ENTRY_LEN as usize]),
+            key: key?,
+            params: self.params,
+            generation: self.generation,
+            backing: self.backing.clone(),
+            index: self.index.clone(),
+            indexed: self.indexed,
+            corrupt: self.corrupt.clone(),
+            degraded: self.degraded,
+        })
+    }
 
+    pub fn check_value(c: &Cipher)
+      -> ::KTag
+    {
         let mut h = ::Keccak::new_keccak512();
         h.update(c.auth());
         h.update(c.f_auth());
-        let mut r = [0u8; 64];
-        h.finalize(&mut r);
 
-        let header = match Header::from_pieces(&*csalt,
-                                               &*asalt,
-                                               &r)
-        {
-            Some(x) => x,
-            None    => return Ok(None),
-        };
+        let mut r = ::KTag([0u8; 64]);
+        h.finalize(&mut *r);
+        r
+    }
+
+    // the master key is xored with the start of the password-derived
+    // keystream, fresh salts on every password change keep it unique
+    pub fn wrap(master: &Cipher,
+                kek: &Cipher)
+      -> [u8; ::cipher::RAW_LEN]
+    {
+        let mut w = [0u8; ::cipher::RAW_LEN];
+        master.write_raw(&mut w);
+
+        ::xcc::stream_xor_ic_inplace(&mut w,
+                                     &kek.nons,
+                                     0,
+                                     &kek.keys);
+        w
+    }
+
+    pub fn unwrap(wrapped: &[u8],
+                  kek: &Cipher)
+      -> ::Result<Cipher>
+    {
+        if wrapped.len() != ::cipher::RAW_LEN
+        { return Err(Error::InvalidLength) }
 
-        f.seek(SeekFrom::Start(0))?;
-        f.write(&header[..])?;
+        let mut raw = wrapped.to_vec();
+
+        ::xcc::stream_xor_ic_inplace(&mut raw,
+                                     &kek.nons,
+                                     0,
+                                     &kek.keys);
+
+        let c = Cipher::from_vecs(&raw[0..56], &raw[56..]);
+        ::memzero(&mut raw);
+
+        c
+    }
+
+    fn create_from(pass: &::Password,
+                   keyfile: Option<&Keyfile>,
+                   path: &str,
+                   params: &KdfParams)
+      -> ::Result<KeyStore>
+    {
+        let csalt = ::Salt::random();
+        let asalt = ::Salt::random();
+
+        let kek = Cipher::from_argon(pass,
+                                     keyfile,
+                                     &*csalt,
+                                     &*asalt,
+                                     params)?;
+
+        let c = Cipher::random()?;
+
+        // hmac over no entries
+        let r = KeyStore::check_value(&c);
+
+        let mut header = Header::from_pieces(&*csalt,
+                                             &*asalt,
+                                             &*r,
+                                             &*KeyStore::check_value(&kek),
+                                             params,
+                                             0,
+                                             &KeyStore::wrap(&c, &kek))
+            .ok_or(Error::InvalidLength)?;
+
+        if keyfile.is_some() {
+            header.set_flags(FLAG_KEYFILE);
+        }
+
+        // never over a keystore that appeared since it was looked for
+        let mut f = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+
+        f.write_all(&header[..])?;
 
         /*println!("******\nwriting csalt: {:?}\nwriting asalt: {:?}",
             &header.csalt(),
             &header.asalt());*/
 
-        Ok(Some(
+        Ok(
         KeyStore {
-            current: Entry([0u8; 160]),
+            current: Entry([0u8; ENTRY_LEN as usize]),
             key: c,
+            params: *params,
+            generation: 0,
             backing: String::from(path),
-            authenticated: true,
-        }))
+            index: HashMap::new(),
+            indexed: 0,
+            corrupt: Vec::new(),
+            degraded: false,
+        })
     }
 
-    pub fn new_from(pass: &str,
-                    path: &str)
-      -> Result<Option<KeyStore>, ::std::io::Error>
+    // params are only used when the keystore doesn't exist yet,
+    // an existing keystore is always opened with its own
+    pub fn new_from(pass: &::Password,
+                    path: &str,
+                    params: &KdfParams)
+      -> ::Result<KeyStore>
     {
-        let mdata = ::std::fs::metadata(path);
+        KeyStore::new_with(pass, None, path, params)
+    }
+
+    // as new_from, a keystore created with a keyfile needs it every
+    // time it's opened after
+    pub fn new_with(pass: &::Password,
+                    keyfile: Option<&Keyfile>,
+                    path: &str,
+                    params: &KdfParams)
+      -> ::Result<KeyStore>
+    {
+        let _held = locked(path)?;
+
+        // only a keystore that isn't there is created, and one created
+        // by someone else in the meantime is opened instead
+        let mdata = match ::std::fs::metadata(path) {
+            Ok(x)  => x,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => {
+                match KeyStore::create_from(pass, keyfile, path, params) {
+                    Err(Error::Io(ref e)) if e.kind() == ::std::io::ErrorKind::AlreadyExists
+                        => ::std::fs::metadata(path)?,
+                    made => return made,
+                }
+            },
+            Err(e) => return Err(Error::Io(e)),
+        };
 
-        if !mdata.is_ok() {
-            return Ok(KeyStore::create_from(pass, path)?)
+        match version_of(path)? {
+            FORMAT_VERSION => (),
+            0              => return Err(Error::LegacyKeystore),
+            v              => return Err(Error::UnsupportedVersion(v)),
         }
 
+        if mdata.len() < HEADER_LEN
+        { return Err(Error::KeystoreTampered) }
+
         let mut f = OpenOptions::new()
             .read(true)
             .write(true)
             .open(path)?;
 
-        let mut header = Header([0u8; 96]);
-        f.read(&mut *header)?;
+        let mut header = Header([0u8; 280]);
+        f.read_exact(&mut *header)?;
+
+        let stored = header.params()
+            .ok_or(Error::InvalidLength)?;
 
-        let c = Cipher::from_argon(pass,
-                                   &header.csalt(),
-                                   &header.asalt(),
-                                   64*1024) // change for actual use
-            .expect("kdf error");
+        match (header.keyfile_required(), keyfile.is_some()) {
+            (true, false) => return Err(Error::KeyfileRequired),
+            (false, true) => return Err(Error::UnexpectedKeyfile),
+            _             => (),
+        }
+
+        let kek = Cipher::from_argon(pass,
+                                     keyfile,
+                                     header.csalt(),
+                                     header.asalt(),
+                                     &stored)?;
 
         /*println!("******\ncsalt: {:?}\nasalt: {:?}",
             &header.csalt(),
             &header.asalt());*/
 
-        let mut h = ::Keccak::new_keccak512();
-        h.update(c.auth());
-        h.update(c.f_auth());
+        if !::memcmp(header.check(), &*KeyStore::check_value(&kek))
+        { return Err(Error::WrongPassword) }
 
-        let len = mdata.unwrap().len();
+        let c = KeyStore::unwrap(header.wrapped(), &kek)?;
 
-        let mut buf = Vec::with_capacity(len as usize);
+        let mut buf = Vec::with_capacity((mdata.len() - HEADER_LEN) as usize);
         f.read_to_end(&mut buf)?;
-        h.update(&buf[..]);
 
-        let mut r = ::KTag([0u8; 64]);
-        h.finalize(&mut *r);
+        // finishing any slot write a crash cut short
+        replay_journal(path, &c, &mut header, &mut buf)?;
 
-        println!("*fn new_from:\n    read: {:?}\n    made: {:?}\n",
-            &header[32..48],
-            &r[..16]);
+        let r = body_hmac(&c, &buf);
 
-        let a = ::memcmp(header.hmac(), &*r);
-
-        Ok(Some(
-        KeyStore {
-            current: Entry([0u8; 160]),
+        let mut ks = KeyStore {
+            current: Entry([0u8; ENTRY_LEN as usize]),
             key: c,
+            params: stored,
+            generation: header.generation(),
             backing: String::from(path),
-            authenticated: a,
-        }))
+            index: HashMap::new(),
+            indexed: 0,
+            corrupt: Vec::new(),
+            degraded: false,
+        };
+
+        ks.reindex(&buf)?;
+
+        // each entry vouches for itself, the hmac for which entries there
+        // are and in what order. a corrupt entry can't match the hmac, but
+        // shouldn't take the intact ones down with it, so the keystore
+        // opens degraded. with the hmac gone entries may also be missing
+        // or rolled back, which nothing may be built on
+        if !::memcmp(header.hmac(), &*r) {
+            if ks.corrupt.is_empty()
+            { return Err(Error::KeystoreTampered) }
+
+            ks.degraded = true;
+        }
+
+        Ok(ks)
     }
 
-    fn update_hmac(&self)
-      -> Result<Option<bool>, ::std::io::Error>
+    // rewraps the master key under new, leaving the entries and
+    // every file encrypted through them untouched
+    pub fn change_password(&mut self,
+                           old: &::Password,
+                           new: &::Password)
+      -> ::Result<()>
     {
-        let mut h = ::Keccak::new_keccak512();
-        h.update(self.key.auth());
-        h.update(self.key.f_auth());
+        self.change_credentials(old, None, new, None)
+    }
 
-        let f = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open(&self.backing)?;
+    // as change_password, also adding, replacing or dropping the keyfile
+    pub fn change_credentials(&mut self,
+                              old: &::Password,
+                              old_keyfile: Option<&Keyfile>,
+                              new: &::Password,
+                              new_keyfile: Option<&Keyfile>)
+      -> ::Result<()>
+    {
+        self.writable()?;
 
-        let mut map = unsafe { ::MmapMut::map_mut(&f)? };
+        let _held = locked(&self.backing)?;
 
-        h.update(&map[96..]);
+        let raw = ::std::fs::read(&self.backing)?;
 
-        let mut r = ::KTag([0u8; 64]);
-        h.finalize(&mut *r);
+        if (raw.len() as u64) < HEADER_LEN
+        { return Err(Error::KeystoreTampered) }
 
-        /*println!("updating with: {:?}\n\n",
-            &r[..16]);*/
+        let mut header = Header([0u8; 280]);
+        header.clone_from_slice(&raw[0..HEADER_LEN as usize]);
 
-        map[32..96].clone_from_slice(&*r);
-        map.flush()?; // this should catch errors and write the relevant entry to a backup
+        if header.keyfile_required() != old_keyfile.is_some() {
+            return Err(if old_keyfile.is_some() { Error::UnexpectedKeyfile } else { Error::KeyfileRequired })
+        }
+
+        let kek = Cipher::from_argon(old,
+                                     old_keyfile,
+                                     header.csalt(),
+                                     header.asalt(),
+                                     &self.params)?;
+
+        if !::memcmp(header.check(), &*KeyStore::check_value(&kek))
+        { return Err(Error::WrongPassword) }
+
+        let master = KeyStore::unwrap(header.wrapped(), &kek)?;
+
+        let csalt = ::Salt::random();
+        let asalt = ::Salt::random();
+
+        let next = Cipher::from_argon(new,
+                                      new_keyfile,
+                                      &*csalt,
+                                      &*asalt,
+                                      &self.params)?;
+
+        let mut out = Header::from_pieces(&*csalt,
+                                          &*asalt,
+                                          header.hmac(),
+                                          &*KeyStore::check_value(&next),
+                                          &self.params,
+                                          header.generation(),
+                                          &KeyStore::wrap(&master, &next))
+            .ok_or(Error::InvalidLength)?;
 
-        Ok(Some(true))
+        let flags = header.flags() & !FLAG_KEYFILE;
+        out.set_flags(if new_keyfile.is_some() { flags | FLAG_KEYFILE } else { flags });
+
+        write_atomic(&self.backing, &[&out[..], &raw[HEADER_LEN as usize..]])
     }
 
-    pub fn add_entry(&self,
-                     name_hash: &[u8],
-                     csalt: &[u8],
-                     asalt: &[u8],
-                     file_hash: &[u8])
-      -> Result<Option<bool>, ::std::io::Error>
+    // writes slot at index, which may be one past the last, along with
+    // the hmac that covers it. journaled first, so a crash part way leaves
+    // either the keystore as it was or what's needed to finish it
+    fn commit_slot(&self,
+                   index: u64,
+                   slot: &[u8])
+      -> ::Result<()>
     {
-        if self.authenticated == false
-        { return Ok(Some(false)) }
-
         let mut f = OpenOptions::new()
             .read(true)
             .write(true)
-            .append(true)
             .open(&self.backing)?;
 
-        let mut ent
+        let mut raw = Vec::new();
+        f.read_to_end(&mut raw)?;
+
+        if (raw.len() as u64) < HEADER_LEN
+        { return Err(Error::KeystoreTampered) }
+
+        let body = with_slot(&raw[HEADER_LEN as usize..], index, slot)
+            .ok_or(Error::KeystoreTampered)?;
+        let hmac = body_hmac(&self.key, &body);
+
+        let jp = journal_path(&self.backing);
+        {
+            let mut j = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&jp)?;
+
+            j.write_all(&index.to_le_bytes())?;
+            j.write_all(slot)?;
+            j.write_all(&*hmac)?;
+            j.sync_all()?;
+        }
+
+        f.seek(SeekFrom::Start(HEADER_LEN + SLOT_LEN * index))?;
+        f.write_all(slot)?;
+        f.seek(SeekFrom::Start(40))?;
+        f.write_all(&*hmac)?;
+        f.sync_data()?;
+
+        ::std::fs::remove_file(&jp)?;
+
+        Ok(())
+    }
+
+    pub fn add_entry(&mut self,
+                     name_hash: &[u8],
+                     ckey: &[u8],
+                     akey: &[u8],
+                     file_hash: &[u8],
+                     path: &[u8])
+      -> ::Result<()>
+    {
+        if path.len() > MAX_PATH_LEN
+        { return Err(Error::PathTooLong) }
+
+        let ent
             = Entry::from_pieces(name_hash,
-                                         csalt,
-                                         asalt,
-                                         file_hash)
-            .expect("no entry");
+                                 ckey,
+                                 akey,
+                                 file_hash,
+                                 path)
+            .ok_or(Error::InvalidLength)?;
+
+        self.add_whole_entry(&ent)
+    }
+
+    // appends e as is, state and chunk size included
+    pub fn add_whole_entry(&mut self, e: &Entry)
+      -> ::Result<()>
+    {
+        self.writable()?;
+
+        let _held = locked(&self.backing)?;
 
         let mdata = ::std::fs::metadata(&self.backing)?;
         let len = mdata.len();
-        let cnt = (len-96)/160;
+        let cnt = (len-HEADER_LEN)/SLOT_LEN;
 
-        println!("*fn add_entry:\n   using ic {}\n",
-            cnt*3);
+        trace!("*fn add_entry:\n   sealing slot {}\n",
+            cnt);
         /*println!("writing entry: {:?}",
-            &ent[..]);*/
+            &e[..]);*/
 
-        ::xcc::stream_xor_ic_inplace(&mut ent[..],
-                                     &self.key.nons,
-                                     cnt*3,
-                                     &self.key.keys);
+        let slot = seal_slot(&self.key, e)?;
 
-        f.seek(SeekFrom::End(0))?;
-        f.write_all(&ent[..])?; // this should catch errors and write the relevant entry to a backup
+        self.commit_slot(cnt, &slot[..])?;
+
+        // only a current index can be extended, a stale one gets rebuilt
+        // on the next lookup anyway
+        if self.indexed == cnt {
+            self.index.entry(e.name().to_vec()).or_insert(cnt);
+            self.indexed = cnt + 1;
+        }
 
-        Ok(self.update_hmac()?) // this should catch errors and write the relevant entry to a backup
+        Ok(())
     }
 
-    pub fn add_whole_entry(&self, e: &Entry)
-      -> Result<Option<bool>, ::std::io::Error>
+    // rebuilds the index from the sealed slots in body, noting any
+    // that fail to open as corrupt
+    fn reindex(&mut self,
+               body: &[u8])
+      -> ::Result<()>
     {
-        Ok(self.add_entry(e.name(), e.crypt(), e.auth(), e.hmac())?)
+        let sl = SLOT_LEN as usize;
+
+        if !body.len().is_multiple_of(sl)
+        { return Err(Error::KeystoreTampered) }
+
+        self.index.clear();
+        self.index.reserve(body.len() / sl);
+        self.corrupt.clear();
+
+        for (i, raw) in body.chunks(sl).enumerate() {
+            match open_slot(&self.key, raw) {
+                Some(e) => { self.index.entry(e.name().to_vec()).or_insert(i as u64); },
+                None    => self.corrupt.push(i as u64),
+            }
+        }
+
+        self.indexed = (body.len() / sl) as u64;
+
+        Ok(())
     }
 
+    // indexes slots appended after the ones already indexed
+    fn extend_index(&mut self,
+                    tail: &[u8])
+      -> ::Result<()>
+    {
+        let sl = SLOT_LEN as usize;
+
+        if !tail.len().is_multiple_of(sl)
+        { return Err(Error::KeystoreTampered) }
+
+        for (i, raw) in tail.chunks(sl).enumerate() {
+            let at = self.indexed + i as u64;
+
+            match open_slot(&self.key, raw) {
+                Some(e) => { self.index.entry(e.name().to_vec()).or_insert(at); },
+                None    => self.corrupt.push(at),
+            }
+        }
+
+        self.indexed += (tail.len() / sl) as u64;
+
+        Ok(())
+    }
+
+    // slots that failed authentication when the index was last built
+    pub fn corrupt_slots(&self)
+      -> &[u64]
+    { &self.corrupt }
+
+    // the hmac didn't match when the keystore was opened
+    pub fn degraded(&self)
+      -> bool
+    { self.degraded }
+
+    fn writable(&self)
+      -> ::Result<()>
+    {
+        if self.degraded
+        { return Err(Error::KeystoreDegraded) }
+
+        Ok(())
+    }
+
+    // makes the entry named name_hash current, returning its slot
     pub fn get_entry(&mut self, name_hash: &[u8])
-      -> Result<Option<u64>, ::std::io::Error>
+      -> ::Result<Option<u64>>
     {
-        if self.authenticated != true
-        { return Ok(None) }
+        let _held = locked(&self.backing)?;
+
+        self.find_entry(name_hash)
+    }
 
+    fn find_entry(&mut self, name_hash: &[u8])
+      -> ::Result<Option<u64>>
+    {
         if name_hash.len() != 64
-        { return Ok(None) }
+        { return Err(Error::InvalidLength) }
+
+        let mut f = OpenOptions::new()
+            .read(true)
+            .open(&self.backing)?;
+
+        let len = f.metadata()?.len();
+
+        if len < HEADER_LEN
+        { return Err(Error::KeystoreTampered) }
+
+        let mut header = Header([0u8; 280]);
+        f.read_exact(&mut *header)?;
+
+        // another keystore open on the same file may have rewritten it since
+        // the index was built, or only appended entries, which just need
+        // adding from where the index left off
+        let count = (len - HEADER_LEN) / SLOT_LEN;
+
+        if header.generation() != self.generation || count < self.indexed {
+            let mut body = Vec::with_capacity((len - HEADER_LEN) as usize);
+            f.read_to_end(&mut body)?;
 
+            self.generation = header.generation();
+            self.reindex(&body)?;
+        } else if count > self.indexed {
+            let mut tail = Vec::with_capacity(((count - self.indexed) * SLOT_LEN) as usize);
+
+            f.seek(SeekFrom::Start(HEADER_LEN + SLOT_LEN * self.indexed))?;
+            f.read_to_end(&mut tail)?;
+
+            self.extend_index(&tail)?;
+        }
+
+        let index = match self.index.get(name_hash) {
+            Some(x) => *x,
+            None    => return Ok(None),
+        };
+
+        let mut raw = [0u8; SLOT_LEN as usize];
+
+        f.seek(SeekFrom::Start(HEADER_LEN + SLOT_LEN * index))?;
+        f.read_exact(&mut raw)?;
+
+        self.current = open_slot(&self.key, &raw)
+            .ok_or(Error::EntryCorrupt)?;
+
+        if !::memcmp(&self.current.0[..64], name_hash)
+        { return Err(Error::KeystoreTampered) }
+
+        Ok(Some(index))
+    }
+
+    // writes ent over the entry with the same name, as is
+    fn update_entry(&mut self,
+                    ent: Entry)
+      -> ::Result<()>
+    {
+        self.writable()?;
+
+        let index = match self.find_entry(ent.name())? {
+            Some(x) => x,
+            None    => return Err(Error::EntryNotFound),
+        };
+
+        self.current = ent;
+
+        let slot = seal_slot(&self.key, &self.current)?;
+
+        self.commit_slot(index, &slot[..])
+    }
+
+    pub fn update_entry_by_tag(&mut self,
+                               idx: &[u8],
+                               tag: &[u8])
+      -> ::Result<()>
+    {
+        let _held = locked(&self.backing)?;
+
+        if self.get_name() != idx && self.find_entry(idx)?.is_none()
+        { return Err(Error::EntryNotFound) }
+
+        if !self.current.update_tag(tag)
+        { return Err(Error::InvalidLength) }
+
+        let tmp = Entry(*self.current);
+        self.update_entry(tmp)
+    }
+
+    pub fn update_entry_by_seal(&mut self,
+                                idx: &[u8],
+                                seal: &Seal)
+      -> ::Result<()>
+    {
+        let _held = locked(&self.backing)?;
+
+        if self.get_name() != idx && self.find_entry(idx)?.is_none()
+        { return Err(Error::EntryNotFound) }
+
+        self.current.update_seal(seal);
+
+        let tmp = Entry(*self.current);
+        self.update_entry(tmp)
+    }
+
+    pub fn update_entry_by_state(&mut self,
+                                 idx: &[u8],
+                                 state: State)
+      -> ::Result<()>
+    {
+        let _held = locked(&self.backing)?;
+
+        if self.get_name() != idx && self.find_entry(idx)?.is_none()
+        { return Err(Error::EntryNotFound) }
+
+        self.current.update_state(state);
+
+        let tmp = Entry(*self.current);
+        self.update_entry(tmp)
+    }
+
+    // every entry that opens, in keystore order
+    pub fn entries(&self)
+      -> ::Result<Vec<Entry>>
+    {
+        let _held = locked(&self.backing)?;
+
+        Ok(self.read_slots()?
+            .into_iter()
+            .filter_map(|s| match s {
+                Slot::Open(e)    => Some(e),
+                Slot::Corrupt(_) => None,
+            })
+            .collect())
+    }
+
+    fn read_slots(&self)
+      -> ::Result<Vec<Slot>>
+    {
+        let raw = ::std::fs::read(&self.backing)?;
+
+        if (raw.len() as u64) < HEADER_LEN
+        { return Err(Error::KeystoreTampered) }
+
+        let sl = SLOT_LEN as usize;
+        let mut out = Vec::with_capacity((raw.len() - HEADER_LEN as usize) / sl);
+
+        for s in raw[HEADER_LEN as usize..].chunks(sl) {
+            if s.len() != sl
+            { return Err(Error::KeystoreTampered) }
+
+            out.push(match open_slot(&self.key, s) {
+                Some(e) => Slot::Open(e),
+                None    => {
+                    let mut raw = [0u8; SLOT_LEN as usize];
+                    raw.clone_from_slice(s);
+                    Slot::Corrupt(raw)
+                },
+            });
+        }
+
+        Ok(out)
+    }
+
+    // reseals slots under fresh nonces, corrupt ones copied as they are,
+    // then swaps the file in atomically
+    fn rewrite(&mut self,
+               slots: &[Slot])
+      -> ::Result<()>
+    {
         let f = OpenOptions::new()
             .read(true)
-            .write(true)
             .open(&self.backing)?;
 
-        let map = unsafe {
-            ::MmapOptions::new()
-                .offset(96)
-                .map_mut(&f)?
-            };
+        let mut header = Header([0u8; 280]);
+        (&f).read_exact(&mut *header)?;
 
-        /*println!("looking for: {:?}",
-            &name_hash[0..16]);*/
+        let generation = header.generation() + 1;
+
+        let mut body = Vec::with_capacity(slots.len() * SLOT_LEN as usize);
+        for s in slots {
+            match *s {
+                Slot::Open(ref e)      => body.extend_from_slice(&seal_slot(&self.key, e)?[..]),
+                Slot::Corrupt(ref raw) => body.extend_from_slice(&raw[..]),
+            }
+        }
 
-        for e in map.chunks(160).enumerate() {
-            self.current.0.clone_from_slice(&e.1[..]);
+        let mut h = ::Keccak::new_keccak512();
+        h.update(self.key.auth());
+        h.update(self.key.f_auth());
+        h.update(&body[..]);
+
+        let mut r = ::KTag([0u8; 64]);
+        h.finalize(&mut *r);
 
-            ::xcc::stream_xor_ic_inplace(&mut self.current.0,
-                                         &self.key.nons,
-                                         (e.0*3) as u64,
-                                         &self.key.keys);
+        header[40..104].clone_from_slice(&*r);
+        header[180..188].clone_from_slice(&generation.to_le_bytes());
 
-            println!("*fn get_entry:\n   iter {} using ic: {}\n",
-                e.0,
-                e.0*3);
+        write_atomic(&self.backing, &[&header[..], &body[..]])?;
 
-            // todo: check how this branch gets interpreted, leaving for now out of curiosity
-            if ::memcmp(&self.current.0[..64], name_hash) {
-                /*println!("\nfound entry:\n{:?}\n",
-                    &self.current[..]);*/
+        self.generation = generation;
+        self.current = Entry([0u8; ENTRY_LEN as usize]);
 
-                return Ok(Some(e.0 as u64))
+        self.index.clear();
+        self.corrupt.clear();
+        for (i, s) in slots.iter().enumerate() {
+            match *s {
+                Slot::Open(ref e)  => { self.index.entry(e.name().to_vec()).or_insert(i as u64); },
+                Slot::Corrupt(_)   => self.corrupt.push(i as u64),
             }
         }
+        self.indexed = slots.len() as u64;
 
-        return Ok(None)
+        Ok(())
     }
 
-    fn update_entry_with_pieces(&mut self,
-                                name_hash: &[u8],
-                                csalt: &[u8],
-                                asalt: &[u8],
-                                file_hash: &[u8])
-        -> Result<Option<bool>, ::std::io::Error>
+    pub fn remove_entry(&mut self,
+                        name_hash: &[u8])
+      -> ::Result<()>
     {
-        if self.authenticated != true
-        { return Ok(Some(false)) }
+        self.writable()?;
 
-        if name_hash.len() != 64 { return Ok(None) }
-        if csalt.len()     != 16 { return Ok(None) }
-        if asalt.len()     != 16 { return Ok(None) }
-        if file_hash.len() != 64 { return Ok(None) }
+        let _held = locked(&self.backing)?;
 
-        let index = match self.get_entry(name_hash)? {
-            Some(x) => x,
-            None    => return Ok(None),
+        let index = match self.find_entry(name_hash)? {
+            Some(x) => x as usize,
+            None    => return Err(Error::EntryNotFound),
         };
 
-        self.current = match Entry::from_pieces(name_hash,
-                                                csalt,
-                                                asalt,
-                                                file_hash)
-        {
+        let mut slots = self.read_slots()?;
+        slots.remove(index);
+
+        self.rewrite(&slots)
+    }
+
+    // swaps in new file keys along with the seal, chunk size and state that go with
+    // them. the keystore is swapped in whole so the old keys are never lost, but
+    // positions don't move so the generation stays and other handles keep their index
+    pub fn rekey_entry(&mut self,
+                       name_hash: &[u8],
+                       ckey: &[u8],
+                       akey: &[u8],
+                       seal: &Seal,
+                       chunk: usize,
+                       state: State)
+      -> ::Result<()>
+    {
+        self.writable()?;
+
+        let _held = locked(&self.backing)?;
+
+        let index = match self.find_entry(name_hash)? {
             Some(x) => x,
-            None    => return Ok(None),
+            None    => return Err(Error::EntryNotFound),
         };
 
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .open(&self.backing)?;
+        let mut ent = Entry(*self.current);
+
+        if !ent.update_keys(ckey, akey)
+        { return Err(Error::InvalidLength) }
+
+        ent.update_seal(seal);
+
+        if !ent.update_chunk(chunk)
+        { return Err(Error::InvalidChunkSize) }
+
+        ent.update_state(state);
+
+        let raw = ::std::fs::read(&self.backing)?;
+
+        if (raw.len() as u64) < HEADER_LEN
+        { return Err(Error::KeystoreTampered) }
 
-        let mut map = unsafe {
-            ::MmapOptions::new()
-                .offset(96 + 160 * index)
-                .len(160)
-                .map_mut(&f)?
-            };
+        let body = with_slot(&raw[HEADER_LEN as usize..], index, &seal_slot(&self.key, &ent)?[..])
+            .ok_or(Error::KeystoreTampered)?;
 
-        let mut out = Entry(self.current.0);
-        ::xcc::stream_xor_ic_inplace(&mut out[..],
-                                     &self.key.nons,
-                                     (index*3) as u64,
-                                     &self.key.keys);
+        let mut header = Header([0u8; 280]);
+        header.clone_from_slice(&raw[0..HEADER_LEN as usize]);
+        header[40..104].clone_from_slice(&*body_hmac(&self.key, &body));
 
-        map.clone_from_slice(&out[..]);
+        write_atomic(&self.backing, &[&header[..], &body[..]])?;
 
-        map.flush()?;
+        self.current = ent;
 
-        Ok(self.update_hmac()?)
+        Ok(())
     }
 
-    fn update_entry(&mut self,
-                    ent: Entry)
-      -> Result<Option<bool>, ::std::io::Error>
+    // moves the entry recorded for old_path over to new_path, both as
+    // crypt::identity gives them
+    pub fn rename_entry(&mut self,
+                        old_path: &str,
+                        new_path: &str)
+      -> ::Result<()>
     {
-        if self.authenticated == false
-        { return Ok(Some(false)) }
+        let old = self.name_of(old_path);
 
-        Ok(self.update_entry_with_pieces(ent.name(),
-                                         ent.crypt(),
-                                         ent.auth(),
-                                         ent.hmac())?)
+        self.rebind_entry(&old[..], new_path)
     }
 
-    pub fn update_entry_by_tag(&mut self,
-                               idx: &[u8],
-                               tag: &[u8])
-      -> Result<Option<bool>, ::std::io::Error>
+    // renames the entry named name_hash after new_path and records
+    // new_path in it, failing if new_path already has an entry. goes
+    // through rewrite so other handles see the name change and reindex
+    pub fn rebind_entry(&mut self,
+                        name_hash: &[u8],
+                        new_path: &str)
+      -> ::Result<()>
     {
-        if self.authenticated == false
-        { return Ok(Some(false)) }
+        self.writable()?;
+
+        if new_path.len() > MAX_PATH_LEN
+        { return Err(Error::PathTooLong) }
+
+        let new = self.name_of(new_path);
 
-        if self.get_name() != idx {
-            if self.get_entry(idx)?.is_none()
-            { return Ok(None) }
+        let _held = locked(&self.backing)?;
+
+        if self.find_entry(&new[..])?.is_some()
+        { return Err(Error::EntryExists) }
+
+        let index = match self.find_entry(name_hash)? {
+            Some(x) => x as usize,
+            None    => return Err(Error::EntryNotFound),
+        };
+
+        let mut slots = self.read_slots()?;
+
+        let ent = match slots[index] {
+            Slot::Open(ref mut e) => e,
+            Slot::Corrupt(_)      => return Err(Error::EntryCorrupt),
+        };
+
+        ent.update_name(&new[..]);
+        ent.update_path(new_path.as_bytes());
+
+        self.rewrite(&slots)
+    }
+
+    // reseals every entry under fresh nonces, dropping corrupt entries
+    // and duplicates that get_entry could never reach, returning how
+    // many entries were dropped. the one write a degraded keystore
+    // takes: what's left authenticates and gets a fresh hmac
+    pub fn compact(&mut self)
+      -> ::Result<usize>
+    {
+        let _held = locked(&self.backing)?;
+
+        let slots  = self.read_slots()?;
+        let before = slots.len();
+
+        let mut seen = ::std::collections::HashSet::with_capacity(before);
+        let mut kept = Vec::with_capacity(before);
+        for s in slots {
+            if let Slot::Open(e) = s {
+                if seen.insert(e.name().to_vec()) {
+                    kept.push(Slot::Open(e));
+                }
+            }
         }
 
-        if !self.current.update_tag(tag)
-        { return Ok(None) }
+        self.rewrite(&kept)?;
+        self.degraded = false;
 
-        let tmp = Entry(self.current.clone());
-        Ok(self.update_entry(tmp)?)
+        Ok(before - kept.len())
+    }
+
+    // a path's entry name, keyed so the keystore doesn't give paths away
+    pub fn name_of(&self,
+                   path: &str)
+      -> ::KTag
+    {
+        let mut name_hash = ::KTag([0u8; 64]);
+
+        let mut h = ::Keccak::new_keccak512();
+
+        h.update(&self.get_own_final()[..]);
+        h.update(path.as_bytes());
+        h.finalize(&mut name_hash.0);
+
+        name_hash
     }
 
     fn get_name(&self)
@@ -456,6 +1357,325 @@ impl KeyStore {
     pub fn get_hmac(&self)
       -> &[u8]
     { self.current.hmac() }
+
+    pub fn get_state(&self)
+      -> State
+    { self.current.state() }
+
+    pub fn get_chunk(&self)
+      -> usize
+    { self.current.chunk() }
+
+    pub fn get_seal(&self)
+      -> Seal
+    { self.current.seal() }
+}
+
+// an entry as read back from its slot
+enum Slot {
+    Open(Entry),
+    Corrupt([u8; SLOT_LEN as usize]),
+}
+
+// binds slots to the format they were sealed under, and from
+// NAMED_SLOTS on to the name they're filed under
+fn slot_ad(version: u32, name: &[u8]) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(72);
+    ad.extend_from_slice(MAGIC);
+    ad.extend_from_slice(&version.to_le_bytes());
+
+    if version >= NAMED_SLOTS {
+        ad.extend_from_slice(name);
+    }
+
+    ad
+}
+
+// where the ciphertext starts in a slot, and the entry bytes it holds
+fn slot_layout(version: u32) -> (usize, usize) {
+    if version >= NAMED_SLOTS { (88, 64) } else { (24, 0) }
+}
+
+// the entry and slot sizes of a format version from v5 on
+pub fn slot_sizes(version: u32) -> (usize, usize) {
+    if version >= LONG_PATHS {
+        (ENTRY_LEN as usize, SLOT_LEN as usize)
+    } else {
+        (SHORT_ENTRY_LEN as usize, SHORT_SLOT_LEN as usize)
+    }
+}
+
+// seals e under the master key with a fresh random nonce
+pub fn seal_slot(key: &Cipher,
+                 e: &Entry)
+  -> ::Result<Vec<u8>>
+{
+    seal_slot_at(key, e, FORMAT_VERSION)
+}
+
+// as seal_slot, for the slots of an older format version
+pub fn seal_slot_at(key: &Cipher,
+                    e: &Entry,
+                    version: u32)
+  -> ::Result<Vec<u8>>
+{
+    if version >= LONG_PATHS
+    { return seal_bytes(key, &e.0[..], version) }
+
+    let mut old = e.to_short().ok_or(Error::PathTooLong)?;
+    let slot    = seal_bytes(key, &old[..], version);
+    ::memzero(&mut old);
+
+    slot
+}
+
+fn seal_bytes(key: &Cipher,
+              plain: &[u8],
+              version: u32)
+  -> ::Result<Vec<u8>>
+{
+    let (el, sl)  = slot_sizes(version);
+    let (at, pre) = slot_layout(version);
+
+    if plain.len() != el
+    { return Err(Error::InvalidLength) }
+
+    let mut slot = vec![0u8; sl];
+    let ad       = slot_ad(version, &plain[..64]);
+
+    let nonce = ::random(24);
+    slot[0..24].clone_from_slice(&nonce);
+    slot[24..24 + pre].clone_from_slice(&plain[..pre]);
+
+    let mut clen = 0u64;
+    let r = unsafe {
+        crypto_aead_xchacha20poly1305_ietf_encrypt(slot[at..].as_mut_ptr(),
+                                                   &mut clen,
+                                                   plain[pre..].as_ptr(),
+                                                   (el - pre) as u64,
+                                                   ad.as_ptr(),
+                                                   ad.len() as u64,
+                                                   ::std::ptr::null(),
+                                                   slot[0..24].as_ptr(),
+                                                   key.keys.0.as_ptr())
+    };
+
+    if r != 0 || clen != (sl - at) as u64
+    { return Err(Error::InvalidLength) }
+
+    Ok(slot)
+}
+
+// the entry sealed in raw, or None if it fails authentication
+pub fn open_slot(key: &Cipher,
+                 raw: &[u8])
+  -> Option<Entry>
+{
+    open_slot_at(key, raw, FORMAT_VERSION)
+}
+
+// as open_slot, for the slots of an older format version, whose
+// entries come back in the current layout
+pub fn open_slot_at(key: &Cipher,
+                    raw: &[u8],
+                    version: u32)
+  -> Option<Entry>
+{
+    if version >= LONG_PATHS {
+        let mut e = Entry([0u8; ENTRY_LEN as usize]);
+        return if open_bytes(key, raw, version, &mut e.0[..]) { Some(e) } else { None }
+    }
+
+    let mut old = [0u8; SHORT_ENTRY_LEN as usize];
+    let e = if open_bytes(key, raw, version, &mut old[..]) { Entry::from_short(&old) } else { None };
+    ::memzero(&mut old);
+
+    e
+}
+
+fn open_bytes(key: &Cipher,
+              raw: &[u8],
+              version: u32,
+              out: &mut [u8])
+  -> bool
+{
+    let (el, sl)  = slot_sizes(version);
+    let (at, pre) = slot_layout(version);
+
+    if raw.len() != sl || out.len() != el
+    { return false }
+
+    let ad     = slot_ad(version, &raw[24..24 + pre]);
+    let mut ml = 0u64;
+
+    out[..pre].clone_from_slice(&raw[24..24 + pre]);
+
+    let r = unsafe {
+        crypto_aead_xchacha20poly1305_ietf_decrypt(out[pre..].as_mut_ptr(),
+                                                   &mut ml,
+                                                   ::std::ptr::null_mut(),
+                                                   raw[at..].as_ptr(),
+                                                   (sl - at) as u64,
+                                                   ad.as_ptr(),
+                                                   ad.len() as u64,
+                                                   raw[0..24].as_ptr(),
+                                                   key.keys.0.as_ptr())
+    };
+
+    r == 0 && ml == (el - pre) as u64
+}
+
+// reports the on-disk format version of the keystore at path,
+// 0 being the unversioned legacy layout
+pub fn version_of(path: &str)
+  -> ::Result<u32>
+{
+    let mut f = OpenOptions::new()
+        .read(true)
+        .open(path)?;
+
+    let len = f.metadata()?.len();
+
+    let mut prefix = [0u8; 8];
+    if len >= 8 {
+        f.read_exact(&mut prefix)?;
+    }
+
+    if &prefix[0..4] == MAGIC {
+        let mut v = [0u8; 4];
+        v.clone_from_slice(&prefix[4..8]);
+        return Ok(u32::from_le_bytes(v))
+    }
+
+    // legacy keystores have no magic, just a fixed header and whole entries
+    if len >= LEGACY_HEADER_LEN && (len - LEGACY_HEADER_LEN).is_multiple_of(160) {
+        return Ok(0)
+    }
+
+    Err(Error::KeystoreTampered)
+}
+
+// replaces the file at path with parts via a synced temp file and a rename,
+// so a crash leaves either the old or the new keystore behind
+// keccak of the auth keys and every slot, kept in the header
+fn body_hmac(key: &Cipher,
+             body: &[u8])
+  -> ::KTag
+{
+    let mut h = ::Keccak::new_keccak512();
+    h.update(key.auth());
+    h.update(key.f_auth());
+    h.update(body);
+
+    let mut r = ::KTag([0u8; 64]);
+    h.finalize(&mut *r);
+    r
+}
+
+// body with slot written at index, or appended when index is one past
+// the last slot
+fn with_slot(body: &[u8],
+             index: u64,
+             slot: &[u8])
+  -> Option<Vec<u8>>
+{
+    let sl = SLOT_LEN as usize;
+    let at = (index as usize).checked_mul(sl)?;
+
+    if slot.len() != sl || !body.len().is_multiple_of(sl) || at > body.len()
+    { return None }
+
+    let mut out = body.to_vec();
+    if at == body.len() {
+        out.extend_from_slice(slot);
+    } else {
+        out[at..at + sl].clone_from_slice(slot);
+    }
+
+    Some(out)
+}
+
+// a slot write in progress: index 8 | sealed slot | hmac 64
+fn journal_path(path: &str)
+  -> String
+{
+    String::from(path) + ".journal"
+}
+
+const JOURNAL_LEN: usize = 8 + SLOT_LEN as usize + 64;
+
+// finishes the slot write a journal describes, if it's whole and the
+// keystore it leaves matches its hmac. any other journal is from a
+// write that never reached the keystore, and is dropped
+fn replay_journal(path: &str,
+                  key: &Cipher,
+                  header: &mut Header,
+                  body: &mut Vec<u8>)
+  -> ::Result<()>
+{
+    let jp = journal_path(path);
+
+    let j = match ::std::fs::read(&jp) {
+        Ok(j)  => j,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::Io(e)),
+    };
+
+    if j.len() == JOURNAL_LEN {
+        let sl = SLOT_LEN as usize;
+
+        let mut w = [0u8; 8];
+        w.clone_from_slice(&j[0..8]);
+        let index = u64::from_le_bytes(w);
+        let slot  = &j[8..8 + sl];
+        let hmac  = &j[8 + sl..];
+
+        if let Some(next) = with_slot(body, index, slot) {
+            if ::memcmp(&*body_hmac(key, &next), hmac) {
+                let mut f = OpenOptions::new()
+                    .write(true)
+                    .open(path)?;
+
+                f.seek(SeekFrom::Start(HEADER_LEN + SLOT_LEN * index))?;
+                f.write_all(slot)?;
+                f.seek(SeekFrom::Start(40))?;
+                f.write_all(hmac)?;
+                f.sync_data()?;
+
+                header[40..104].clone_from_slice(hmac);
+                *body = next;
+            }
+        }
+    }
+
+    ::std::fs::remove_file(&jp)?;
+
+    Ok(())
+}
+
+pub fn write_atomic(path: &str,
+                    parts: &[&[u8]])
+  -> ::Result<()>
+{
+    let tmp = String::from(path) + ".tmp";
+
+    {
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?;
+
+        for p in parts {
+            f.write_all(p)?;
+        }
+
+        f.sync_all()?;
+    }
+
+    ::std::fs::rename(&tmp, path)?;
+
+    Ok(())
 }
 
 impl ::std::ops::Index<::std::ops::Range<usize>> for Header {
@@ -546,16 +1766,16 @@ impl Drop for Header {
 }
 
 impl ::std::ops::Deref for Header {
-    type Target = [u8; 96];
+    type Target = [u8; 280];
 
-    fn deref(&self) -> &[u8; 96] {
+    fn deref(&self) -> &[u8; 280] {
         &self.0
     }
 }
 
 impl ::std::ops::DerefMut for Header {
     #[inline]
-    fn deref_mut(&mut self) -> &mut [u8; 96] {
+    fn deref_mut(&mut self) -> &mut [u8; 280] {
         &mut self.0
     }
 }
@@ -648,16 +1868,16 @@ impl Drop for Entry {
 }
 
 impl ::std::ops::Deref for Entry {
-    type Target = [u8; 160];
+    type Target = [u8; ENTRY_LEN as usize];
 
-    fn deref(&self) -> &[u8; 160] {
+    fn deref(&self) -> &[u8; ENTRY_LEN as usize] {
         &self.0
     }
 }
 
 impl ::std::ops::DerefMut for Entry {
     #[inline]
-    fn deref_mut(&mut self) -> &mut [u8; 160] {
+    fn deref_mut(&mut self) -> &mut [u8; ENTRY_LEN as usize] {
         &mut self.0
     }
 }
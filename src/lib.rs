@@ -2,14 +2,38 @@
 extern crate argon2;
 extern crate blake2_rfc;
 extern crate chashmap;
+#[cfg(unix)]
+extern crate libc;
 extern crate memmap;
 extern crate rayon;
 extern crate rust_sodium;
+extern crate rust_sodium_sys;
 extern crate tiny_keccak;
 
+// progress and timing traces, on stderr with the trace feature and
+// compiled out otherwise, so callers never have to sift through them
+#[cfg(feature = "trace")]
+macro_rules! trace {
+    ($($arg:tt)*) => { eprintln!($($arg)*) }
+}
+
+#[cfg(not(feature = "trace"))]
+macro_rules! trace {
+    ($($arg:tt)*) => { if false { eprintln!($($arg)*) } }
+}
+
 pub mod cipher;
 pub mod crypt;
+pub mod error;
+pub mod journal;
 pub mod key_store;
+pub mod migrate;
+pub mod secret;
+pub mod stream;
+pub mod tag;
+
+pub use error::{Error, Result};
+pub use secret::Password;
 
 use memmap::MmapMut as MmapMut;
 use memmap::MmapOptions as MmapOptions;
@@ -29,13 +53,19 @@ impl Salt {
     pub fn from_slice(raw: &[u8]) -> Option<Salt> {
         if raw.len() != 16 { return None }
         let mut k = [0u8; 16];
-
-        (0..16).for_each(|i| {
-            k[i]   = raw[i].clone();
-        });
+        k.copy_from_slice(raw);
 
         Some(Salt(k))
     }
+
+    pub fn random() -> Salt {
+        let mut r = random(16);
+        let mut k = [0u8; 16];
+        k.copy_from_slice(&r[..]);
+        memzero(&mut r);
+
+        Salt(k)
+    }
 }
 
 impl Drop for Salt {
@@ -110,10 +140,8 @@ impl KTag {
     pub fn from_slice(raw: &[u8]) -> Option<KTag> {
         if raw.len() != 64 { return None }
         let mut k = [0u8; 64];
+        k.copy_from_slice(raw);
 
-        (0..64).for_each(|i| {
-            k[i] = raw[i].clone();
-        });
         Some(KTag(k))
     }
 }
@@ -192,38 +220,1284 @@ mod tests {
     //use std::io::prelude::*;
     //use std::io::SeekFrom;
 
+    // fresh directory per test so keystores don't collide when run in parallel
+    fn scratch(test: &str, contents: &[u8]) -> String {
+        let dir = std::env::temp_dir().join(format!("salt_map_{}", test));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).expect("couldn't create scratch dir");
+
+        let path = dir.join("mars.gif");
+        std::fs::write(&path, contents).expect("couldn't write scratch file");
+
+        path.to_str().unwrap().to_string()
+    }
+
+    // cheap argon2 settings so the tests don't spend their time in the kdf
+    fn fast() -> cipher::KdfParams {
+        cipher::KdfParams {
+            mem_cost: 256,
+            time_cost: 1,
+            lanes: 1,
+        }
+    }
+
+    fn pass(s: &str) -> Password {
+        Password::new(String::from(s)).expect("test password too short")
+    }
+
+    fn sample() -> Vec<u8> {
+        (0..(3*1024*1024 + 123)).map(|i| (i % 251) as u8).collect()
+    }
+
     #[test]
     fn test_encrypt() {
         use crypt::Crypt as Crypt;
 
-        let paswd      = "YaGet16CharsWhaddayaGet";
-        let path       = "mars.gif";
-        let mut test_crypt = Crypt::init(paswd, path)
-            .expect("couldn't init crypt!")
-            .unwrap();
+        let paswd      = &pass("YaGet16CharsWhaddayaGet");
+        let path       = scratch("encrypt", &sample());
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
 
-        let e_result = test_crypt.encrypt()
+        test_crypt.encrypt()
             .expect("couldn't encrypt!");
 
-        assert!(e_result.unwrap());
+        assert!(std::fs::read(&path).unwrap() != sample());
+        assert!(test_crypt.authenticate().unwrap().intact());
+
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks = key_store::KeyStore::new_from(paswd, ks_path.to_str().unwrap(), &fast())
+            .expect("couldn't open keystore!");
+        let listed = ks.entries().unwrap();
+
+        assert!(listed.len() == 1);
+        // entries record the path relative to the keystore
+        assert!(listed[0].path() == b"mars.gif");
     }
 
     #[test]
     fn test_decrypt() {
         use crypt::Crypt as Crypt;
 
-        std::thread::sleep(std::time::Duration::from_secs(3));
+        let paswd      = &pass("YaGet16CharsWhaddayaGet");
+        let path       = scratch("decrypt", &sample());
 
-        let paswd      = "YaGet16CharsWhaddayaGet";
-        let path       = "mars.gif";
-        let mut test_crypt = Crypt::init(paswd, path)
+        Crypt::init(paswd, &path, &fast())
             .expect("couldn't init crypt!")
-            .unwrap();
+            .encrypt()
+            .expect("couldn't encrypt!");
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        test_crypt.decrypt()
+            .expect("couldn't decrypt!");
+
+        assert!(std::fs::read(&path).unwrap() == sample());
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        use crypt::Crypt as Crypt;
+
+        let path = scratch("wrong_password", &sample());
+
+        Crypt::init(&pass("YaGet16CharsWhaddayaGet"), &path, &fast())
+            .expect("couldn't init crypt!");
+
+        match Crypt::init(&pass("NotTheRightPasswordAtAll"), &path, &fast()) {
+            Err(Error::WrongPassword) => (),
+            _                         => panic!("expected WrongPassword"),
+        }
+
+        match Password::new(String::from("short")) {
+            Err(Error::PasswordTooShort) => (),
+            _                            => panic!("expected PasswordTooShort"),
+        }
+    }
+
+    #[test]
+    fn test_kdf_params_from_header() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("kdf_params", &sample());
+
+        Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!")
+            .encrypt()
+            .expect("couldn't encrypt!");
+
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks = key_store::KeyStore::new_from(paswd,
+                                               ks_path.to_str().unwrap(),
+                                               &cipher::KdfParams::default())
+            .expect("couldn't open keystore!");
+
+        assert!(ks.params == fast());
+
+        Crypt::init(paswd, &path, &cipher::KdfParams::default())
+            .expect("couldn't init crypt!")
+            .decrypt()
+            .expect("couldn't decrypt!");
+
+        assert!(std::fs::read(&path).unwrap() == sample());
+
+        // the header isn't authenticated until argon2 has run, so
+        // doctored costs must be refused before they reach it
+        let ks_path = ks_path.to_str().unwrap();
+        let mut raw = std::fs::read(ks_path).unwrap();
+        raw[168..172].clone_from_slice(&0xFFFFFFF0u32.to_le_bytes());
+        std::fs::write(ks_path, &raw).unwrap();
+
+        match key_store::KeyStore::new_from(paswd, ks_path, &fast()) {
+            Err(Error::InvalidKdfParams) => (),
+            _                            => panic!("expected InvalidKdfParams"),
+        }
+
+        raw[168..172].clone_from_slice(&fast().mem_cost.to_le_bytes());
+        raw[176..180].clone_from_slice(&0u32.to_le_bytes());
+        std::fs::write(ks_path, &raw).unwrap();
+
+        match key_store::KeyStore::new_from(paswd, ks_path, &fast()) {
+            Err(Error::InvalidKdfParams) => (),
+            _                            => panic!("expected InvalidKdfParams"),
+        }
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("migrate_legacy", &sample());
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks_path = ks_path.to_str().unwrap();
+
+        // hand-build a v0 keystore: csalt, asalt, hmac, then one entry
+        let csalt = Salt::random();
+        let asalt = Salt::random();
+        let c = cipher::Cipher::from_argon(paswd, None, &*csalt, &*asalt, &fast()).unwrap();
+
+        let mut ent = [7u8; 160];
+        xcc::stream_xor_ic_inplace(&mut ent[..], &c.nons, 0, &c.keys);
+
+        let mut h = Keccak::new_keccak512();
+        h.update(c.auth());
+        h.update(c.f_auth());
+        h.update(&ent[..]);
+        let mut hmac = [0u8; 64];
+        h.finalize(&mut hmac);
+
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&*csalt);
+        raw.extend_from_slice(&*asalt);
+        raw.extend_from_slice(&hmac);
+        raw.extend_from_slice(&ent);
+        std::fs::write(ks_path, &raw).unwrap();
+
+        assert!(key_store::version_of(ks_path).unwrap() == 0);
+        match Crypt::init(paswd, &path, &fast()) {
+            Err(Error::LegacyKeystore) => (),
+            _                          => panic!("expected LegacyKeystore"),
+        }
+
+        match migrate::migrate(&pass("NotTheRightPasswordAtAll"), ks_path, &fast()) {
+            Err(Error::WrongPassword) => (),
+            _                         => panic!("expected WrongPassword"),
+        }
+
+        assert!(migrate::migrate(paswd, ks_path, &fast()).unwrap() == 0);
+        assert!(key_store::version_of(ks_path).unwrap() == key_store::FORMAT_VERSION);
+        assert!(migrate::migrate(paswd, ks_path, &fast()).unwrap() == key_store::FORMAT_VERSION);
+
+        let mut ks = key_store::KeyStore::new_from(paswd, ks_path, &fast())
+            .expect("couldn't open migrated keystore!");
+
+        // the entry's argon2 salts were traded for the file key they derive
+        let file = cipher::Cipher::from_argon(paswd, None, &[7u8; 16], &[7u8; 16], &fast()).unwrap();
+        let mut raw = [0u8; cipher::RAW_LEN];
+        file.write_raw(&mut raw);
+
+        assert!(ks.get_entry(&[7u8; 64]).unwrap() == Some(0));
+        assert!(ks.get_crypt_key() == &raw[0..56]);
+        assert!(ks.get_auth_key() == &raw[56..]);
+        assert!(ks.entries().unwrap()[0].path().is_empty());
+        assert!(ks.get_state() == key_store::State::Unknown);
+    }
+
+    #[test]
+    fn test_remove_and_compact() {
+        let paswd   = &pass("YaGet16CharsWhaddayaGet");
+        let path    = scratch("remove_entry", b"");
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks_path = ks_path.to_str().unwrap();
+
+        let mut ks = key_store::KeyStore::new_from(paswd, ks_path, &fast())
+            .expect("couldn't create keystore!");
+
+        for i in 1..4u8 {
+            ks.add_entry(&[i; 64], &[i; 56], &[i; 32], &[i; 64], &[b'a' + i]).unwrap();
+        }
+        ks.add_entry(&[3u8; 64], &[9u8; 56], &[9u8; 32], &[9u8; 64], b"dup").unwrap();
+
+        let before = std::fs::read(ks_path).unwrap();
+
+        ks.remove_entry(&[2u8; 64]).unwrap();
+
+        match ks.remove_entry(&[2u8; 64]) {
+            Err(Error::EntryNotFound) => (),
+            _                         => panic!("expected EntryNotFound"),
+        }
+
+        let after = std::fs::read(ks_path).unwrap();
+        let hl    = key_store::HEADER_LEN as usize;
+        let el    = key_store::SLOT_LEN as usize;
+
+        assert!(after.len() == before.len() - el);
+        // entry 0 is unchanged but gets resealed under a fresh nonce
+        assert!(after[hl..hl+el] != before[hl..hl+el]);
+
+        assert!(ks.compact().unwrap() == 1);
+
+        let mut ks = key_store::KeyStore::new_from(paswd, ks_path, &fast())
+            .expect("couldn't reopen keystore!");
+
+        assert!(ks.get_entry(&[1u8; 64]).unwrap() == Some(0));
+        assert!(ks.get_entry(&[2u8; 64]).unwrap().is_none());
+        assert!(ks.get_entry(&[3u8; 64]).unwrap() == Some(1));
+        assert!(ks.get_crypt_key() == &[3u8; 56][..]);
+        assert!(std::fs::metadata(ks_path).unwrap().len() == (hl + 2*el) as u64);
+
+        let paths: Vec<Vec<u8>> = ks.entries().unwrap()
+            .iter()
+            .map(|e| e.path().to_vec())
+            .collect();
+        assert!(paths == vec![b"b".to_vec(), b"d".to_vec()]);
+    }
+
+    #[test]
+    fn test_keyfile() {
+        use cipher::Keyfile as Keyfile;
+        use key_store::KeyStore as KeyStore;
+
+        let paswd   = &pass("YaGet16CharsWhaddayaGet");
+        let path    = scratch("keyfile", b"something you have");
+        let other   = std::path::Path::new(&path).with_file_name("other.key");
+        let other   = other.to_str().unwrap();
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks_path = ks_path.to_str().unwrap();
+        std::fs::write(other, b"something else").unwrap();
+
+        let key = Keyfile::read(&path).unwrap();
+
+        let ks = KeyStore::new_with(paswd, Some(&key), ks_path, &fast())
+            .expect("couldn't create keystore!");
+        let name = ks.name_of("mars.gif");
+        drop(ks);
+
+        let mut raw = [0u8; key_store::HEADER_LEN as usize];
+        raw.clone_from_slice(&std::fs::read(ks_path).unwrap()[..key_store::HEADER_LEN as usize]);
+        assert!(key_store::Header(raw).keyfile_required());
+
+        match KeyStore::new_from(paswd, ks_path, &fast()) {
+            Err(Error::KeyfileRequired) => (),
+            _                           => panic!("expected KeyfileRequired"),
+        }
+        match KeyStore::new_with(paswd, Some(&Keyfile::read(other).unwrap()), ks_path, &fast()) {
+            Err(Error::WrongPassword) => (),
+            _                         => panic!("expected WrongPassword"),
+        }
+
+        let mut ks = KeyStore::new_with(paswd, Some(&key), ks_path, &fast())
+            .expect("couldn't open keystore!");
+        assert!(ks.name_of("mars.gif")[..] == name[..]);
+
+        // dropping the keyfile keeps the same master key
+        ks.change_credentials(paswd, Some(&key), paswd, None).unwrap();
+
+        match KeyStore::new_with(paswd, Some(&key), ks_path, &fast()) {
+            Err(Error::UnexpectedKeyfile) => (),
+            _                             => panic!("expected UnexpectedKeyfile"),
+        }
+        let ks = KeyStore::new_from(paswd, ks_path, &fast())
+            .expect("couldn't open keystore!");
+        assert!(ks.name_of("mars.gif")[..] == name[..]);
+
+        std::fs::write(other, b"").unwrap();
+        assert!(Keyfile::read(other).is_err());
+    }
+
+    #[test]
+    fn test_central_keystore() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let a     = scratch("central_a", &sample());
+        let b     = scratch("central_b", b"");
+        // deep enough that its absolute path outgrows a v7 entry
+        let deep  = std::path::Path::new(&b).with_file_name(vec!["d".repeat(100); 3].join("/"));
+        std::fs::create_dir_all(&deep).unwrap();
+        let b     = deep.join("far.gif").to_str().unwrap().to_string();
+        std::fs::write(&b, b"elsewhere").unwrap();
+        assert!(b.len() > key_store::SHORT_PATH_LEN);
+        let ks    = scratch("central_ks", b"");
+        let ks    = std::path::Path::new(&ks).with_file_name("central.keystore");
+        let ks    = ks.to_str().unwrap();
+
+        Crypt::init_in(paswd, &a, ks, &fast())
+            .expect("couldn't init crypt!")
+            .encrypt()
+            .expect("couldn't encrypt!");
+
+        // one unlock for both files
+        let store = key_store::KeyStore::new_from(paswd, ks, &fast())
+            .expect("couldn't open keystore!");
+
+        let mut crypt = Crypt::with_keystore(store, &b)
+            .expect("couldn't init crypt!");
+        crypt.encrypt().expect("couldn't encrypt!");
+
+        let mut crypt = Crypt::with_keystore(crypt.into_keystore(), &a)
+            .expect("couldn't init crypt!");
+        crypt.decrypt().expect("couldn't decrypt!");
+        assert!(std::fs::read(&a).unwrap() == sample());
+
+        let store = crypt.into_keystore();
+        let paths: Vec<Vec<u8>> = store.entries().unwrap()
+            .iter()
+            .map(|e| e.path().to_vec())
+            .collect();
+        // both live outside the keystore's directory
+        let full = |p: &str| std::fs::canonicalize(p).unwrap().to_str().unwrap().as_bytes().to_vec();
+        assert!(paths == vec![full(&a), full(&b)]);
+
+        assert!(std::fs::metadata(crypt::keystore_for(&a)).is_err());
+        assert!(std::fs::metadata(crypt::keystore_for(&b)).is_err());
+    }
+
+    #[test]
+    fn test_shared_keystore() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("shared_keystore", b"");
+        let dir   = std::path::Path::new(&path).parent().unwrap().to_path_buf();
+
+        let files: Vec<String> = (0..8)
+            .map(|i| {
+                let f = dir.join(format!("{}.bin", i));
+                std::fs::write(&f, vec![i as u8; 4096 * (i + 1)]).unwrap();
+                f.to_str().unwrap().to_string()
+            })
+            .collect();
+
+        let ks = key_store::KeyStore::new_from(paswd, &crypt::keystore_for(&path), &fast())
+            .expect("couldn't open keystore!");
+
+        // handles on one file from many threads, one unlock between them
+        let work: Vec<_> = files.iter()
+            .map(|f| {
+                let shared = ks.share().unwrap();
+                let f = f.clone();
+                std::thread::spawn(move || {
+                    Crypt::with_keystore(shared, &f)
+                        .expect("couldn't init crypt!")
+                        .encrypt()
+                        .expect("couldn't encrypt!");
+                })
+            })
+            .collect();
+        work.into_iter().for_each(|t| t.join().unwrap());
+
+        assert!(ks.entries().unwrap().len() == files.len());
+
+        // encrypts reseal their slot in place, nothing moved so nobody
+        // else's index was thrown away
+        let reopened = key_store::KeyStore::new_from(paswd, &crypt::keystore_for(&path), &fast())
+            .expect("couldn't reopen keystore!");
+        assert!(reopened.generation == ks.generation);
+
+        for (i, f) in files.iter().enumerate() {
+            Crypt::with_keystore(ks.share().unwrap(), f)
+                .expect("couldn't init crypt!")
+                .decrypt()
+                .expect("couldn't decrypt!");
+            assert!(std::fs::read(f).unwrap() == vec![i as u8; 4096 * (i + 1)]);
+        }
+    }
+
+    #[test]
+    fn test_keystore_index() {
+        let paswd   = &pass("YaGet16CharsWhaddayaGet");
+        let path    = scratch("keystore_index", b"");
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks_path = ks_path.to_str().unwrap();
+
+        let mut a = key_store::KeyStore::new_from(paswd, ks_path, &fast())
+            .expect("couldn't create keystore!");
+
+        for i in 1..200u8 {
+            a.add_entry(&[i; 64], &[i; 56], &[i; 32], &[i; 64], &[i]).unwrap();
+        }
+        a.add_entry(&[7u8; 64], &[0u8; 56], &[0u8; 32], &[0u8; 64], b"dup").unwrap();
+
+        assert!(a.get_entry(&[150u8; 64]).unwrap() == Some(149));
+        assert!(a.get_crypt_key() == &[150u8; 56][..]);
+        // the first of two entries with a name is the one found
+        assert!(a.get_entry(&[7u8; 64]).unwrap() == Some(6));
+        assert!(a.get_crypt_key() == &[7u8; 56][..]);
+        assert!(a.get_entry(&[0u8; 64]).unwrap().is_none());
+
+        let mut b = key_store::KeyStore::new_from(paswd, ks_path, &fast())
+            .expect("couldn't reopen keystore!");
+        assert!(b.get_entry(&[199u8; 64]).unwrap() == Some(198));
+
+        // changes made through one keystore are seen by the other
+        a.add_entry(&[200u8; 64], &[200u8; 56], &[200u8; 32], &[200u8; 64], b"new").unwrap();
+        assert!(b.get_entry(&[200u8; 64]).unwrap() == Some(200));
+
+        b.remove_entry(&[1u8; 64]).unwrap();
+        assert!(a.get_entry(&[1u8; 64]).unwrap().is_none());
+        assert!(a.get_entry(&[2u8; 64]).unwrap() == Some(0));
+        assert!(a.get_auth_key() == &[2u8; 32][..]);
+        assert!(a.get_entry(&[200u8; 64]).unwrap() == Some(199));
+    }
+
+    #[test]
+    fn test_change_password() {
+        use crypt::Crypt as Crypt;
+
+        let old   = &pass("YaGet16CharsWhaddayaGet");
+        let new   = &pass("AnotherSixteenPlusChars");
+        let path  = scratch("change_password", &sample());
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks_path = ks_path.to_str().unwrap();
+
+        Crypt::init(old, &path, &fast())
+            .expect("couldn't init crypt!")
+            .encrypt()
+            .expect("couldn't encrypt!");
+
+        let encrypted = std::fs::read(&path).unwrap();
+        let mut ks = key_store::KeyStore::new_from(old, ks_path, &fast()).unwrap();
+
+        match ks.change_password(new, old) {
+            Err(Error::WrongPassword) => (),
+            _                         => panic!("expected WrongPassword"),
+        }
+
+        ks.change_password(old, new).unwrap();
+
+        assert!(std::fs::read(&path).unwrap() == encrypted);
+        match Crypt::init(old, &path, &fast()) {
+            Err(Error::WrongPassword) => (),
+            _                         => panic!("expected WrongPassword"),
+        }
+
+        Crypt::init(new, &path, &fast())
+            .expect("couldn't init crypt!")
+            .decrypt()
+            .expect("couldn't decrypt!");
+
+        assert!(std::fs::read(&path).unwrap() == sample());
+    }
+
+    #[test]
+    fn test_tampered_file() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("tampered_file", &sample());
+
+        Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!")
+            .encrypt()
+            .expect("couldn't encrypt!");
+
+        let mut raw = std::fs::read(&path).unwrap();
+        raw[1024*1024 + 7] ^= 1;
+        std::fs::write(&path, &raw).unwrap();
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        match test_crypt.decrypt() {
+            Err(Error::FileTampered) => (),
+            _                        => panic!("expected FileTampered"),
+        }
+        assert!(std::fs::read(&path).unwrap() == raw);
+    }
+
+    #[test]
+    fn test_tampered_keystore() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("tampered_keystore", &sample());
+
+        let other = std::path::Path::new(&path).with_file_name("other.gif");
+        let other = other.to_str().unwrap();
+        std::fs::write(other, b"other").unwrap();
+
+        Crypt::init(paswd, other, &fast())
+            .expect("couldn't init crypt!")
+            .encrypt()
+            .expect("couldn't encrypt!");
+        Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!")
+            .encrypt()
+            .expect("couldn't encrypt!");
+
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let clean   = std::fs::read(&ks_path).unwrap();
+
+        // a bad entry is caught on its own and the rest stay usable
+        let mut raw = clean.clone();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        std::fs::write(&ks_path, &raw).unwrap();
+
+        match Crypt::init(paswd, &path, &fast()) {
+            Err(Error::EntryCorrupt) => (),
+            _                        => panic!("expected EntryCorrupt"),
+        }
+        Crypt::init(paswd, other, &fast())
+            .expect("couldn't use the intact entry!");
+
+        // but without the hmac nothing is written until it's compacted
+        match Crypt::init(paswd, other, &fast()).unwrap().decrypt() {
+            Err(Error::KeystoreDegraded) => (),
+            _                            => panic!("expected KeystoreDegraded"),
+        }
+
+        let mut ks = key_store::KeyStore::new_from(paswd, ks_path.to_str().unwrap(), &fast())
+            .expect("couldn't open keystore!");
+        assert!(ks.corrupt_slots() == &[1][..]);
+        assert!(ks.degraded());
+        assert!(ks.compact().unwrap() == 1);
+        assert!(ks.corrupt_slots().is_empty());
+        assert!(!ks.degraded());
+
+        // entries that all open but aren't the ones the hmac covers
+        let mut raw = clean.clone();
+        let hl = key_store::HEADER_LEN as usize;
+        let sl = key_store::SLOT_LEN as usize;
+        raw.truncate(hl + sl);
+        std::fs::write(&ks_path, &raw).unwrap();
+
+        match Crypt::init(paswd, &path, &fast()) {
+            Err(Error::KeystoreTampered) => (),
+            _                            => panic!("expected KeystoreTampered"),
+        }
+
+        // a dropped entry hidden behind a garbage one still leaves it degraded
+        raw.extend_from_slice(&vec![0x5a; sl]);
+        std::fs::write(&ks_path, &raw).unwrap();
+
+        let ks = key_store::KeyStore::new_from(paswd, ks_path.to_str().unwrap(), &fast())
+            .expect("couldn't open keystore!");
+        assert!(ks.degraded());
+        assert!(ks.corrupt_slots() == &[1][..]);
+
+        // a slot's name is bound into its seal
+        let mut raw = clean.clone();
+        raw[hl + 24] ^= 1;
+        std::fs::write(&ks_path, &raw).unwrap();
+
+        let ks = key_store::KeyStore::new_from(paswd, ks_path.to_str().unwrap(), &fast())
+            .expect("couldn't open keystore!");
+        assert!(ks.corrupt_slots() == &[0][..]);
+    }
+
+    #[test]
+    fn test_state() {
+        use crypt::Crypt as Crypt;
+        use key_store::State as State;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("state", &sample());
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        assert!(test_crypt.status().unwrap() == State::Plain);
+        match test_crypt.decrypt() {
+            Err(Error::EntryNotFound) => (),
+            _                         => panic!("expected EntryNotFound"),
+        }
+
+        test_crypt.encrypt().expect("couldn't encrypt!");
+        let encrypted = std::fs::read(&path).unwrap();
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        assert!(test_crypt.status().unwrap() == State::Encrypted);
+        match test_crypt.encrypt() {
+            Err(Error::AlreadyEncrypted) => (),
+            _                            => panic!("expected AlreadyEncrypted"),
+        }
+        assert!(std::fs::read(&path).unwrap() == encrypted);
+
+        // an entry from before states were kept, over a file that's still
+        // ciphertext, keeps the only keys that open it
+        let ks_path = crypt::keystore_for(&path);
+        let mut ks  = key_store::KeyStore::new_from(paswd, &ks_path, &fast()).unwrap();
+        let name    = ks.name_of("mars.gif");
+        ks.update_entry_by_state(&name[..], State::Unknown).unwrap();
+
+        let mut test_crypt = Crypt::with_keystore(ks, &path)
+            .expect("couldn't init crypt!");
+
+        match test_crypt.encrypt_writer(Vec::new()) {
+            Err(Error::AlreadyEncrypted) => (),
+            _                            => panic!("expected AlreadyEncrypted"),
+        }
+
+        test_crypt.decrypt().expect("couldn't decrypt!");
+        assert!(test_crypt.status().unwrap() == State::Plain);
+        assert!(std::fs::read(&path).unwrap() == sample());
+    }
+
+    #[test]
+    fn test_path_identity() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("identity", &sample());
+        let dir   = std::path::Path::new(&path).parent().unwrap().to_str().unwrap().to_string();
+        let other = dir.clone() + "/other.gif";
+        std::fs::write(&other, b"never encrypted").unwrap();
+
+        Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!")
+            .encrypt()
+            .expect("couldn't encrypt!");
+
+        // another spelling of the same file finds the same entry
+        let spelled = format!("{}/./../salt_map_identity/mars.gif", dir);
+        Crypt::init(paswd, &spelled, &fast())
+            .expect("couldn't init crypt!")
+            .decrypt()
+            .expect("couldn't decrypt!");
+        assert!(std::fs::read(&path).unwrap() == sample());
+
+        // a file the keystore doesn't know is an error, not a new entry
+        match Crypt::init(paswd, &other, &fast()).unwrap().decrypt() {
+            Err(Error::EntryNotFound) => (),
+            _                         => panic!("expected EntryNotFound"),
+        }
+        assert!(std::fs::read(&other).unwrap() == b"never encrypted");
+
+        let ks = key_store::KeyStore::new_from(paswd, &crypt::keystore_for(&path), &fast())
+            .expect("couldn't open keystore!");
+        assert!(ks.entries().unwrap().len() == 1);
+    }
+
+    #[test]
+    fn test_rename() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("rename", &sample());
+        let dir   = std::path::Path::new(&path).parent().unwrap().to_str().unwrap().to_string();
+        let moved = dir.clone() + "/moved.gif";
+        let away  = dir.clone() + "/away.gif";
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+        test_crypt.encrypt().expect("couldn't encrypt!");
+        test_crypt.rename(&moved).expect("couldn't rename!");
+
+        assert!(std::fs::metadata(&path).is_err());
+        assert!(std::fs::metadata(tag::sidecar_for(&moved)).is_ok());
+
+        // moved outside the tool, the old path has nothing and the new
+        // one has to be reattached
+        std::fs::rename(&moved, &away).unwrap();
+
+        match Crypt::init(paswd, &away, &fast()).unwrap().decrypt() {
+            Err(Error::EntryNotFound) => (),
+            _                         => panic!("expected EntryNotFound"),
+        }
+
+        let mut test_crypt = Crypt::init(paswd, &away, &fast())
+            .expect("couldn't init crypt!");
+        assert!(test_crypt.reattach().unwrap() == Some(String::from("moved.gif")));
+
+        Crypt::init(paswd, &away, &fast())
+            .expect("couldn't init crypt!")
+            .decrypt()
+            .expect("couldn't decrypt!");
+        assert!(std::fs::read(&away).unwrap() == sample());
+
+        let mut ks = key_store::KeyStore::new_from(paswd, &crypt::keystore_for(&path), &fast())
+            .expect("couldn't open keystore!");
+        let listed = ks.entries().unwrap();
+        assert!(listed.len() == 1 && listed[0].path() == b"away.gif");
+
+        let other = ks.name_of("other.gif");
+        ks.add_entry(&other[..], &[7u8; 56], &[7u8; 32], &[7u8; 64], b"other.gif").unwrap();
+        match ks.rename_entry("other.gif", "away.gif") {
+            Err(Error::EntryExists) => (),
+            _                       => panic!("expected EntryExists"),
+        }
+        match ks.rename_entry("missing.gif", "elsewhere.gif") {
+            Err(Error::EntryNotFound) => (),
+            _                         => panic!("expected EntryNotFound"),
+        }
+    }
 
-        let d_result = test_crypt.decrypt()
+    #[test]
+    fn test_password_sources() {
+        use secret::Source as Source;
+
+        let paswd = "YaGet16CharsWhaddayaGet";
+        let path  = scratch("password_sources", format!("{}\nnot the password\n", paswd).as_bytes());
+
+        let file = Source::parse(&format!("file:{}", path)).unwrap();
+        assert!(file.read().unwrap().as_str() == paswd);
+
+        std::env::set_var("SALT_MAP_TEST_PASSWORD", paswd);
+        let env = Source::parse("env:SALT_MAP_TEST_PASSWORD").unwrap();
+        assert!(env.read().unwrap().as_str() == paswd);
+        assert!(std::env::var_os("SALT_MAP_TEST_PASSWORD").is_none());
+        assert!(env.read().is_err());
+
+        let cmd = Source::parse(&format!("cmd:printf '{}\\r\\n'", paswd)).unwrap();
+        assert!(cmd.read().unwrap().as_str() == paswd);
+        assert!(Source::parse("cmd:exit 3").unwrap().read().is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+            let fd = std::fs::File::open(&path).unwrap().into_raw_fd();
+            let from_fd = Source::parse(&format!("fd:{}", fd)).unwrap();
+            assert!(from_fd.read().unwrap().as_str() == paswd);
+
+            // read through a duplicate, the descriptor itself stays open
+            let f = unsafe { std::fs::File::from_raw_fd(fd) };
+            assert!(f.metadata().is_ok());
+
+            assert!(Source::Fd(1).read().is_err());
+            assert!(Source::Fd(2).read().is_err());
+        }
+
+        std::fs::write(&path, vec![b'x'; secret::MAX_SECRET + 1]).unwrap();
+        assert!(file.read().is_err());
+
+        std::fs::write(&path, "fifteen  chars\nand more after it\n").unwrap();
+        match file.read() {
+            Err(Error::PasswordTooShort) => (),
+            _                            => panic!("expected PasswordTooShort"),
+        }
+
+        assert!(Source::parse("fd:three").is_none());
+        assert!(Source::parse("file:").is_none());
+        assert!(Source::parse("stdin").is_none());
+    }
+
+    #[test]
+    fn test_rekey() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("rekey", &sample());
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        test_crypt.encrypt().expect("couldn't encrypt!");
+        let first = std::fs::read(&path).unwrap();
+        test_crypt.decrypt().expect("couldn't decrypt!");
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        test_crypt.encrypt().expect("couldn't encrypt!");
+        let second = std::fs::read(&path).unwrap();
+
+        // same plaintext, so any shared keystream would show up as equal bytes
+        let same = first.iter().zip(second.iter()).filter(|b| b.0 == b.1).count();
+        assert!(same < first.len() / 128);
+
+        Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!")
+            .decrypt()
             .expect("couldn't decrypt!");
 
-        assert!(d_result.unwrap());
+        assert!(std::fs::read(&path).unwrap() == sample());
+    }
+
+    #[test]
+    fn test_keystore_journal() {
+        use crypt::Crypt as Crypt;
+
+        let paswd   = &pass("YaGet16CharsWhaddayaGet");
+        let path    = scratch("keystore_journal", &sample());
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks_str  = ks_path.to_str().unwrap();
+
+        Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!")
+            .encrypt()
+            .expect("couldn't encrypt!");
+        let before = std::fs::read(&ks_path).unwrap();
+
+        Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!")
+            .decrypt()
+            .expect("couldn't decrypt!");
+        let after = std::fs::read(&ks_path).unwrap();
+
+        // crash after the slot landed but before its hmac did
+        let mut torn = after.clone();
+        torn[40..104].clone_from_slice(&before[40..104]);
+        std::fs::write(&ks_path, &torn).unwrap();
+
+        match key_store::KeyStore::new_from(paswd, ks_str, &fast()) {
+            Err(Error::KeystoreTampered) => (),
+            _                            => panic!("expected KeystoreTampered"),
+        }
+
+        let hl = key_store::HEADER_LEN as usize;
+        let sl = key_store::SLOT_LEN as usize;
+        let mut journal = 0u64.to_le_bytes().to_vec();
+        journal.extend_from_slice(&after[hl..hl + sl]);
+        journal.extend_from_slice(&after[40..104]);
+        std::fs::write(format!("{}.journal", ks_str), &journal).unwrap();
+
+        key_store::KeyStore::new_from(paswd, ks_str, &fast())
+            .expect("journal wasn't replayed");
+
+        assert!(std::fs::read(&ks_path).unwrap() == after);
+        assert!(!std::path::Path::new(&format!("{}.journal", ks_str)).exists());
+
+        // a journal that never reached the keystore leaves it alone
+        journal[8] ^= 1;
+        std::fs::write(format!("{}.journal", ks_str), &journal).unwrap();
+
+        key_store::KeyStore::new_from(paswd, ks_str, &fast())
+            .expect("couldn't open keystore!");
+
+        assert!(std::fs::read(&ks_path).unwrap() == after);
+        assert!(!std::path::Path::new(&format!("{}.journal", ks_str)).exists());
+
+        // a missing target gets no entry
+        let gone = std::path::Path::new(&path).with_file_name("gone.gif");
+        let mut missing = Crypt::init(paswd, gone.to_str().unwrap(), &fast())
+            .expect("couldn't init crypt!");
+
+        assert!(missing.encrypt().is_err());
+
+        let ks = key_store::KeyStore::new_from(paswd, ks_str, &fast())
+            .expect("couldn't open keystore!");
+        assert!(ks.entries().unwrap().len() == 1);
+    }
+
+    #[test]
+    fn test_keystore_lock() {
+        use std::os::unix::io::AsRawFd;
+
+        let paswd   = &pass("YaGet16CharsWhaddayaGet");
+        let path    = scratch("keystore_lock", b"");
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks_str  = ks_path.to_str().unwrap();
+
+        key_store::KeyStore::new_from(paswd, ks_str, &fast())
+            .expect("couldn't create keystore!");
+
+        // another process's view of the lock, its own open of the file
+        let other = std::fs::File::open(key_store::lock_path(ks_str))
+            .expect("no lock file beside the keystore");
+        let try_lock = || unsafe { libc::flock(other.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+
+        let held = key_store::locked(ks_str).unwrap();
+        assert!(try_lock() != 0);
+
+        drop(held);
+        assert!(try_lock() == 0);
+    }
+
+    #[test]
+    fn test_stream() {
+        use crypt::Crypt as Crypt;
+        use std::io::{Read, Write};
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("stream", &sample());
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        // odd sized writes so chunks straddle them
+        let mut w = test_crypt.encrypt_writer(Vec::new()).unwrap();
+        for piece in sample().chunks(777_777) {
+            w.write_all(piece).unwrap();
+        }
+        let sealed = test_crypt.finish_writer(w).unwrap();
+
+        // the mmap path has to agree with the stream on every byte of the tag
+        std::fs::write(&path, &sealed).unwrap();
+        assert!(test_crypt.authenticate().unwrap().intact());
+
+        let mut opened = Vec::new();
+        test_crypt.decrypt_reader(&sealed[..]).unwrap()
+            .read_to_end(&mut opened)
+            .unwrap();
+        assert!(opened == sample());
+
+        let mut bad = sealed.clone();
+        bad[2*1024*1024 + 3] ^= 1;
+        let mut r = test_crypt.decrypt_reader(&bad[..]).unwrap();
+        match r.read_to_end(&mut Vec::new()) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::InvalidData => (),
+            _                                                         => panic!("expected InvalidData"),
+        }
+        assert!(r.read(&mut [0u8; 16]).is_err());
+
+        test_crypt.decrypt().expect("couldn't decrypt!");
+        assert!(std::fs::read(&path).unwrap() == sample());
+
+        // empty files can't be mapped, but still encrypt and decrypt
+        let empty = scratch("stream_empty", b"");
+        let mut test_crypt = Crypt::init(paswd, &empty, &fast())
+            .expect("couldn't init crypt!");
+
+        test_crypt.encrypt().expect("couldn't encrypt!");
+        test_crypt.decrypt().expect("couldn't decrypt!");
+        assert!(std::fs::read(&empty).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_out_of_place() {
+        use crypt::Crypt as Crypt;
+        use key_store::State as State;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("out_of_place", &sample());
+        let sealed = std::path::Path::new(&path).with_file_name("mars.gif.enc");
+        let sealed = sealed.to_str().unwrap();
+        let opened = std::path::Path::new(&path).with_file_name("mars.out.gif");
+        let opened = opened.to_str().unwrap();
+
+        Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!")
+            .encrypt_to(sealed)
+            .expect("couldn't encrypt!");
+
+        assert!(std::fs::read(&path).unwrap() == sample());
+        assert!(std::fs::read(sealed).unwrap() != sample());
+        assert!(std::fs::metadata(String::from(sealed) + ".tmp").is_err());
+
+        let mut test_crypt = Crypt::init(paswd, sealed, &fast())
+            .expect("couldn't init crypt!");
+
+        assert!(test_crypt.status().unwrap() == State::Encrypted);
+        test_crypt.decrypt_to(opened).expect("couldn't decrypt!");
+
+        assert!(std::fs::read(opened).unwrap() == sample());
+        assert!(test_crypt.status().unwrap() == State::Encrypted);
+
+        // plaintext written elsewhere is the owner's alone
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            assert!(std::fs::metadata(opened).unwrap().permissions().mode() & 0o777 == 0o600);
+            assert!(std::fs::metadata(sealed).unwrap().permissions().mode() & 0o777 == 0o600);
+        }
+
+        let mut raw = std::fs::read(sealed).unwrap();
+        raw[1024*1024 + 7] ^= 1;
+        std::fs::write(sealed, &raw).unwrap();
+        std::fs::remove_file(opened).unwrap();
+
+        match test_crypt.decrypt_to(opened) {
+            Err(Error::FileTampered) => (),
+            _                        => panic!("expected FileTampered"),
+        }
+        assert!(std::fs::metadata(opened).is_err());
+        assert!(std::fs::metadata(String::from(opened) + ".tmp").is_err());
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        use crypt::Crypt as Crypt;
+        use std::io::Read;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("chunk_size", &sample());
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        match test_crypt.set_chunk_size(100) {
+            Err(Error::InvalidChunkSize) => (),
+            _                            => panic!("expected InvalidChunkSize"),
+        }
+
+        test_crypt.set_chunk_size(4096).unwrap();
+        test_crypt.encrypt().expect("couldn't encrypt!");
+
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let ks = key_store::KeyStore::new_from(paswd, ks_path.to_str().unwrap(), &fast())
+            .unwrap();
+        assert!(ks.entries().unwrap()[0].chunk() == 4096);
+
+        // a fresh crypt only knows the size from the entry
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        assert!(test_crypt.authenticate().unwrap().intact());
+
+        let mut opened = Vec::new();
+        test_crypt.decrypt_reader(std::fs::File::open(&path).unwrap()).unwrap()
+            .read_to_end(&mut opened)
+            .unwrap();
+        assert!(opened == sample());
+
+        test_crypt.decrypt().expect("couldn't decrypt!");
+        assert!(std::fs::read(&path).unwrap() == sample());
+    }
+
+    #[test]
+    fn test_recover() {
+        use crypt::{Crypt, Recovery};
+        use journal::{Journal, Op};
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("recover", &sample());
+        let mb    = 1024*1024;
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        assert!(test_crypt.recover(Recovery::Finish).unwrap().is_none());
+
+        test_crypt.encrypt().expect("couldn't encrypt!");
+        let encrypted = std::fs::read(&path).unwrap();
+        test_crypt.decrypt().expect("couldn't decrypt!");
+
+        let ks_path = std::path::Path::new(&path).with_file_name(".keystore");
+        let mut ks = key_store::KeyStore::new_from(paswd, ks_path.to_str().unwrap(), &fast())
+            .unwrap();
+
+        let mut name = [0u8; 64];
+        let mut h = Keccak::new_keccak512();
+        h.update(&ks.get_own_final()[..]);
+        h.update(b"mars.gif");
+        h.finalize(&mut name);
+
+        ks.get_entry(&name).unwrap().expect("missing entry");
+        let auth = ks.get_auth_key()[0..16].to_vec();
+
+        // an encrypt that journaled chunk 1 and died halfway through writing it
+        let interrupt = || {
+            let mut raw = sample();
+            raw[..mb + mb/2].copy_from_slice(&encrypted[..mb + mb/2]);
+            std::fs::write(&path, &raw).unwrap();
+
+            let mut j = Journal::create(&path, Op::Encrypt, raw.len() as u64, mb as u64, 0, 4, 1, &auth)
+                .unwrap();
+            j.record(1, &encrypted[mb..2*mb], &auth).unwrap();
+        };
+
+        interrupt();
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        match test_crypt.decrypt() {
+            Err(Error::Interrupted) => (),
+            _                       => panic!("expected Interrupted"),
+        }
+        match test_crypt.encrypt() {
+            Err(Error::Interrupted) => (),
+            _                       => panic!("expected Interrupted"),
+        }
+
+        assert!(test_crypt.recover(Recovery::Finish).unwrap() == Some(Op::Encrypt));
+        assert!(std::fs::read(&path).unwrap() == encrypted);
+        assert!(!Journal::exists(&path));
+
+        test_crypt.decrypt().expect("couldn't decrypt!");
+        assert!(std::fs::read(&path).unwrap() == sample());
+
+        interrupt();
+
+        assert!(test_crypt.recover(Recovery::RollBack).unwrap() == Some(Op::Encrypt));
+        assert!(std::fs::read(&path).unwrap() == sample());
+        assert!(!Journal::exists(&path));
+    }
+
+    #[test]
+    fn test_tag_binding() {
+        use crypt::Crypt as Crypt;
+        use std::io::Read;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("tag_binding", &sample());
+        let mb    = 1024*1024;
+
+        // a crypt that encrypted still trusts its file, each check needs a fresh one
+        let fresh = || Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        fresh().encrypt().expect("couldn't encrypt!");
+
+        let encrypted = std::fs::read(&path).unwrap();
+
+        let mut swapped = encrypted.clone();
+        swapped[0..mb].clone_from_slice(&encrypted[mb..2*mb]);
+        swapped[mb..2*mb].clone_from_slice(&encrypted[0..mb]);
+        std::fs::write(&path, &swapped).unwrap();
+
+        match fresh().decrypt() {
+            Err(Error::Reordered) => (),
+            _                     => panic!("expected Reordered"),
+        }
+        assert!(std::fs::read(&path).unwrap() == swapped);
+
+        let mut opened = Vec::new();
+        let err = fresh().decrypt_reader(std::fs::File::open(&path).unwrap()).unwrap()
+            .read_to_end(&mut opened)
+            .unwrap_err();
+        match Error::from(err) {
+            Error::Reordered => (),
+            _                => panic!("expected Reordered from the reader"),
+        }
+
+        std::fs::write(&path, &encrypted[..encrypted.len() - 100]).unwrap();
+
+        match fresh().decrypt() {
+            Err(Error::Truncated) => (),
+            _                     => panic!("expected Truncated"),
+        }
+
+        let mut extended = encrypted.clone();
+        extended.extend_from_slice(&[0u8; 100]);
+        std::fs::write(&path, &extended).unwrap();
+
+        match fresh().decrypt() {
+            Err(Error::Extended) => (),
+            _                    => panic!("expected Extended"),
+        }
+
+        std::fs::write(&path, &encrypted).unwrap();
+
+        fresh().decrypt().expect("couldn't decrypt!");
+        assert!(std::fs::read(&path).unwrap() == sample());
+    }
+
+    #[test]
+    fn test_read_range() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("read_range", &sample());
+        let plain = sample();
+        let mb    = 1024*1024;
+
+        let mut test_crypt = Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+        test_crypt.encrypt().expect("couldn't encrypt!");
+
+        let sidecar = tag::sidecar_for(&path);
+        assert!(std::fs::metadata(&sidecar).is_ok());
+
+        assert!(test_crypt.read_range(mb as u64 - 10, 20).unwrap()[..] == plain[mb - 10..mb + 10]);
+        assert!(test_crypt.read_range(3*mb as u64, 123).unwrap()[..] == plain[3*mb..]);
+        assert!(test_crypt.read_range(7, 0).unwrap().is_empty());
+
+        match test_crypt.read_range(3*mb as u64, 124) {
+            Err(Error::InvalidLength) => (),
+            _                         => panic!("expected InvalidLength"),
+        }
+
+        // a missing sidecar costs one full check and is written back
+        std::fs::remove_file(&sidecar).unwrap();
+        assert!(test_crypt.read_range(5, 5).unwrap()[..] == plain[5..10]);
+        assert!(std::fs::metadata(&sidecar).is_ok());
+
+        // damage in the last chunk only fails reads that touch it
+        let mut raw = std::fs::read(&path).unwrap();
+        raw[3*mb + 7] ^= 1;
+        std::fs::write(&path, &raw).unwrap();
+
+        assert!(test_crypt.read_range(0, mb).unwrap()[..] == plain[0..mb]);
+
+        match test_crypt.read_range(3*mb as u64 - 1, 2) {
+            Err(Error::FileTampered) => (),
+            _                        => panic!("expected FileTampered"),
+        }
+        assert!(std::fs::read(&path).unwrap() == raw);
+
+        raw[3*mb + 7] ^= 1;
+        std::fs::write(&path, &raw).unwrap();
+
+        test_crypt.decrypt().expect("couldn't decrypt!");
+        assert!(std::fs::read(&path).unwrap() == plain);
+        assert!(std::fs::metadata(&sidecar).is_err());
+    }
+
+    #[test]
+    fn test_damage_report() {
+        use crypt::Crypt as Crypt;
+
+        let paswd = &pass("YaGet16CharsWhaddayaGet");
+        let path  = scratch("damage_report", &sample());
+        let mb    = 1024*1024;
+
+        let fresh = || Crypt::init(paswd, &path, &fast())
+            .expect("couldn't init crypt!");
+
+        fresh().encrypt().expect("couldn't encrypt!");
+
+        let encrypted = std::fs::read(&path).unwrap();
+        let sidecar   = tag::sidecar_for(&path);
+
+        let report = fresh().authenticate().unwrap();
+        assert!(report.intact());
+        assert!(report.damaged.is_empty());
+
+        let mut raw = encrypted.clone();
+        raw[mb + 1] ^= 1;
+        raw[2*mb + 2] ^= 1;
+        std::fs::write(&path, &raw).unwrap();
+
+        let report = fresh().authenticate().unwrap();
+        match report.fault {
+            Some(Error::FileTampered) => (),
+            _                         => panic!("expected FileTampered"),
+        }
+        assert!(report.damaged.len() == 1 && report.damaged[0] == (1..3));
+        assert!(report.byte_ranges().len() == 1 && report.byte_ranges()[0] == (mb as u64..3*mb as u64));
+
+        std::fs::write(&path, &encrypted[..encrypted.len() - 100]).unwrap();
+
+        let report = fresh().authenticate().unwrap();
+        match report.fault {
+            Some(Error::Truncated) => (),
+            _                      => panic!("expected Truncated"),
+        }
+        assert!(report.damaged.len() == 1 && report.damaged[0] == (3..4));
+        assert!(report.byte_ranges().len() == 1 && report.byte_ranges()[0] == (3*mb as u64..encrypted.len() as u64));
+
+        // with nothing to compare against, the whole file is suspect
+        std::fs::remove_file(&sidecar).unwrap();
+        std::fs::write(&path, &raw).unwrap();
+
+        let report = fresh().authenticate().unwrap();
+        assert!(!report.intact());
+        assert!(report.damaged.len() == 1 && report.damaged[0] == (0..4));
+
+        // a clean pass writes the sidecar back
+        std::fs::write(&path, &encrypted).unwrap();
+
+        assert!(fresh().authenticate().unwrap().intact());
+        assert!(std::fs::metadata(&sidecar).is_ok());
     }
 
     /*#[test]
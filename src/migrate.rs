@@ -0,0 +1,436 @@
+/// upgrades keystores written in older on-disk formats
+/// to the current format version, in place
+use ::cipher::Cipher as Cipher;
+use ::cipher::KdfParams as KdfParams;
+use ::cipher::Keyfile as Keyfile;
+use ::key_store::{FORMAT_VERSION, LEGACY_HEADER_LEN, MAGIC};
+use ::key_store::Entry as Entry;
+use ::key_store::KeyStore as KeyStore;
+use ::Error as Error;
+
+// each step rewrites the keystore one version forward and only
+// depends on the byte layouts of the two versions it connects
+
+// derives the password key from a v1 or later header, which all share
+// the same prefix up to the kdf params. up to v3 it is also the key
+// the entries are encrypted under. only v6 and later can need a keyfile
+fn open_cipher(pass: &::Password,
+               keyfile: Option<&Keyfile>,
+               raw: &[u8])
+  -> ::Result<Cipher>
+{
+    if raw.len() < 180
+    { return Err(Error::KeystoreTampered) }
+
+    let params = KdfParams::from_bytes(&raw[168..180])
+        .ok_or(Error::InvalidLength)?;
+
+    let c = Cipher::from_argon(pass,
+                               keyfile,
+                               &raw[8..24],
+                               &raw[24..40],
+                               &params)?;
+
+    if !::memcmp(&raw[104..168], &*KeyStore::check_value(&c))
+    { return Err(Error::WrongPassword) }
+
+    Ok(c)
+}
+
+fn entries_hmac(c: &Cipher,
+                body: &[u8])
+  -> ::KTag
+{
+    let mut h = ::Keccak::new_keccak512();
+    h.update(c.auth());
+    h.update(c.f_auth());
+    h.update(body);
+
+    let mut r = ::KTag([0u8; 64]);
+    h.finalize(&mut *r);
+    r
+}
+
+// legacy keystores hard-coded their argon2 settings at build time,
+// KdfParams::default() matches the stock build. returns the version
+// the keystore was migrated from
+pub fn migrate(pass: &::Password,
+               path: &str,
+               legacy: &KdfParams)
+  -> ::Result<u32>
+{
+    migrate_with(pass, None, path, legacy)
+}
+
+// as migrate, for keystores created with a keyfile
+pub fn migrate_with(pass: &::Password,
+                    keyfile: Option<&Keyfile>,
+                    path: &str,
+                    legacy: &KdfParams)
+  -> ::Result<u32>
+{
+    let _held = ::key_store::locked(path)?;
+
+    let from = ::key_store::version_of(path)?;
+
+    if from > FORMAT_VERSION
+    { return Err(Error::UnsupportedVersion(from)) }
+
+    let mut v = from;
+    while v < FORMAT_VERSION {
+        match v {
+            0 => from_v0(pass, path, legacy)?,
+            1 => from_v1(path)?,
+            2 => from_v2(pass, path)?,
+            3 => from_v3(pass, path)?,
+            4 => from_v4(pass, path)?,
+            5 => from_v5(pass, path)?,
+            6 => from_v6(pass, keyfile, path)?,
+            7 => from_v7(pass, keyfile, path)?,
+            _ => return Err(Error::UnsupportedVersion(v)),
+        }
+
+        v = ::key_store::version_of(path)?;
+    }
+
+    Ok(from)
+}
+
+// v0: csalt 16 | asalt 16 | hmac 64, entries at ic index*3
+// v1: magic 4 | version 4 | csalt 16 | asalt 16 | hmac 64 | check 64 | params 12
+fn from_v0(pass: &::Password,
+           path: &str,
+           params: &KdfParams)
+  -> ::Result<()>
+{
+    let raw = ::std::fs::read(path)?;
+    let hl  = LEGACY_HEADER_LEN as usize;
+
+    let c = Cipher::from_argon(pass,
+                               None,
+                               &raw[0..16],
+                               &raw[16..32],
+                               params)?;
+
+    let mut h = ::Keccak::new_keccak512();
+    h.update(c.auth());
+    h.update(c.f_auth());
+    h.update(&raw[hl..]);
+
+    let mut r = ::KTag([0u8; 64]);
+    h.finalize(&mut *r);
+
+    // v0 has no check value, so a wrong password (or wrong legacy
+    // params) can't be told apart from a tampered keystore
+    if !::memcmp(&raw[32..96], &*r)
+    { return Err(Error::WrongPassword) }
+
+    // entries keep their keys and keystream positions, and the hmac
+    // covers the same bytes, so only the header is rewritten
+    let mut header = [0u8; 180];
+
+    header[0..4].clone_from_slice(MAGIC);
+    header[4..8].clone_from_slice(&1u32.to_le_bytes());
+    header[8..40].clone_from_slice(&raw[0..32]);
+    header[40..104].clone_from_slice(&*r);
+    header[104..168].clone_from_slice(&*KeyStore::check_value(&c));
+    header[168..180].clone_from_slice(&params.to_bytes());
+
+    ::key_store::write_atomic(path, &[&header[..], &raw[hl..]])
+}
+
+// v2 appends the entries' keystream base to the v1 header,
+// v1 entries all start from block 0
+fn from_v1(path: &str)
+  -> ::Result<()>
+{
+    let raw = ::std::fs::read(path)?;
+
+    if raw.len() < 180
+    { return Err(Error::KeystoreTampered) }
+
+    let mut header = [0u8; 188];
+
+    header[0..180].clone_from_slice(&raw[0..180]);
+    header[4..8].clone_from_slice(&2u32.to_le_bytes());
+
+    ::key_store::write_atomic(path, &[&header[..], &raw[180..]])
+}
+
+// v3 grows entries from 160 to 512 bytes to hold a length prefixed
+// path, which v2 entries never recorded and are left empty
+fn from_v2(pass: &::Password,
+           path: &str)
+  -> ::Result<()>
+{
+    let raw = ::std::fs::read(path)?;
+    let c   = open_cipher(pass, None, &raw)?;
+
+    if raw.len() < 188 || !(raw.len() - 188).is_multiple_of(160)
+    { return Err(Error::KeystoreTampered) }
+
+    if !::memcmp(&raw[40..104], &*entries_hmac(&c, &raw[188..]))
+    { return Err(Error::KeystoreTampered) }
+
+    let mut b = [0u8; 8];
+    b.clone_from_slice(&raw[180..188]);
+    let base = u64::from_le_bytes(b);
+
+    let cnt      = ((raw.len() - 188) / 160) as u64;
+    let new_base = base + cnt*3;
+
+    let mut body = Vec::with_capacity(cnt as usize * 512);
+    for e in raw[188..].chunks(160).enumerate() {
+        let mut ent = [0u8; 512];
+        ent[0..160].clone_from_slice(e.1);
+
+        ::xcc::stream_xor_ic_inplace(&mut ent[0..160],
+                                     &c.nons,
+                                     base + e.0 as u64 * 3,
+                                     &c.keys);
+
+        ::xcc::stream_xor_ic_inplace(&mut ent[..],
+                                     &c.nons,
+                                     new_base + e.0 as u64 * 8,
+                                     &c.keys);
+
+        body.extend_from_slice(&ent);
+        ::memzero(&mut ent);
+    }
+
+    let mut header = [0u8; 188];
+
+    header.clone_from_slice(&raw[0..188]);
+    header[4..8].clone_from_slice(&3u32.to_le_bytes());
+    header[40..104].clone_from_slice(&*entries_hmac(&c, &body));
+    header[180..188].clone_from_slice(&new_base.to_le_bytes());
+
+    ::key_store::write_atomic(path, &[&header[..], &body[..]])
+}
+
+// v4 wraps the keystore key under a password key with fresh salts, so
+// the old keystore key becomes the master key and names stay valid.
+// entries trade their argon2 salts for the file keys derived from them
+fn from_v3(pass: &::Password,
+           path: &str)
+  -> ::Result<()>
+{
+    let raw = ::std::fs::read(path)?;
+    let c   = open_cipher(pass, None, &raw)?;
+
+    if raw.len() < 188 || !(raw.len() - 188).is_multiple_of(512)
+    { return Err(Error::KeystoreTampered) }
+
+    if !::memcmp(&raw[40..104], &*entries_hmac(&c, &raw[188..]))
+    { return Err(Error::KeystoreTampered) }
+
+    let params = KdfParams::from_bytes(&raw[168..180])
+        .ok_or(Error::InvalidLength)?;
+
+    let mut b = [0u8; 8];
+    b.clone_from_slice(&raw[180..188]);
+    let base = u64::from_le_bytes(b);
+
+    let cnt      = ((raw.len() - 188) / 512) as u64;
+    let new_base = base + cnt*8;
+
+    let mut body = Vec::with_capacity(cnt as usize * 512);
+    for e in raw[188..].chunks(512).enumerate() {
+        let mut old = [0u8; 512];
+        old.clone_from_slice(e.1);
+
+        ::xcc::stream_xor_ic_inplace(&mut old[..],
+                                     &c.nons,
+                                     base + e.0 as u64 * 8,
+                                     &c.keys);
+
+        let file = Cipher::from_argon(pass,
+                                      None,
+                                      &old[64..80],
+                                      &old[80..96],
+                                      &params)?;
+
+        let mut ent = [0u8; 512];
+        ent[0..64].clone_from_slice(&old[0..64]);
+        file.write_raw(&mut ent[64..152]);
+        ent[152..216].clone_from_slice(&old[96..160]);
+        ent[216..474].clone_from_slice(&old[160..418]);
+
+        ::xcc::stream_xor_ic_inplace(&mut ent[..],
+                                     &c.nons,
+                                     new_base + e.0 as u64 * 8,
+                                     &c.keys);
+
+        body.extend_from_slice(&ent);
+        ::memzero(&mut old);
+    }
+
+    let csalt = ::Salt::random();
+    let asalt = ::Salt::random();
+    let kek   = Cipher::from_argon(pass, None, &*csalt, &*asalt, &params)?;
+
+    let mut header = [0u8; 276];
+
+    header[0..188].clone_from_slice(&raw[0..188]);
+    header[4..8].clone_from_slice(&4u32.to_le_bytes());
+    header[8..24].clone_from_slice(&*csalt);
+    header[24..40].clone_from_slice(&*asalt);
+    header[40..104].clone_from_slice(&*entries_hmac(&c, &body));
+    header[104..168].clone_from_slice(&*KeyStore::check_value(&kek));
+    header[180..188].clone_from_slice(&new_base.to_le_bytes());
+    header[188..276].clone_from_slice(&KeyStore::wrap(&c, &kek));
+
+    ::key_store::write_atomic(path, &[&header[..], &body[..]])
+}
+
+// v5 seals each 512 byte entry on its own with xchacha20-poly1305 under
+// the master key, in 552 byte slots. the base becomes a generation count
+fn from_v4(pass: &::Password,
+           path: &str)
+  -> ::Result<()>
+{
+    let raw = ::std::fs::read(path)?;
+    let kek = open_cipher(pass, None, &raw)?;
+
+    if raw.len() < 276 || !(raw.len() - 276).is_multiple_of(512)
+    { return Err(Error::KeystoreTampered) }
+
+    let c = KeyStore::unwrap(&raw[188..276], &kek)?;
+
+    if !::memcmp(&raw[40..104], &*entries_hmac(&c, &raw[276..]))
+    { return Err(Error::KeystoreTampered) }
+
+    let mut b = [0u8; 8];
+    b.clone_from_slice(&raw[180..188]);
+    let base = u64::from_le_bytes(b);
+
+    let cnt = ((raw.len() - 276) / 512) as u64;
+
+    let mut body = Vec::with_capacity(cnt as usize * ::key_store::SHORT_SLOT_LEN as usize);
+    for e in raw[276..].chunks(512).enumerate() {
+        let mut old = [0u8; 512];
+        old.clone_from_slice(e.1);
+
+        ::xcc::stream_xor_ic_inplace(&mut old[..],
+                                     &c.nons,
+                                     base + e.0 as u64 * 8,
+                                     &c.keys);
+
+        let ent = Entry::from_short(&old).ok_or(Error::InvalidLength)?;
+        ::memzero(&mut old);
+
+        body.extend_from_slice(&::key_store::seal_slot_at(&c, &ent, 5)?[..]);
+    }
+
+    let mut header = [0u8; 276];
+
+    header.clone_from_slice(&raw[0..276]);
+    header[4..8].clone_from_slice(&5u32.to_le_bytes());
+    header[40..104].clone_from_slice(&*entries_hmac(&c, &body));
+    header[180..188].clone_from_slice(&0u64.to_le_bytes());
+
+    ::key_store::write_atomic(path, &[&header[..], &body[..]])
+}
+
+// v6 appends a flags word to the header, 0 since only new keystores can
+// ask for a keyfile. slots bind the format version, so they're resealed
+fn from_v5(pass: &::Password,
+           path: &str)
+  -> ::Result<()>
+{
+    let raw = ::std::fs::read(path)?;
+    let kek = open_cipher(pass, None, &raw)?;
+
+    let sl = ::key_store::SHORT_SLOT_LEN as usize;
+
+    if raw.len() < 276 || !(raw.len() - 276).is_multiple_of(sl)
+    { return Err(Error::KeystoreTampered) }
+
+    let c = KeyStore::unwrap(&raw[188..276], &kek)?;
+
+    if !::memcmp(&raw[40..104], &*entries_hmac(&c, &raw[276..]))
+    { return Err(Error::KeystoreTampered) }
+
+    let mut body = Vec::with_capacity(raw.len() - 276);
+    for s in raw[276..].chunks(sl) {
+        let ent = ::key_store::open_slot_at(&c, s, 5)
+            .ok_or(Error::EntryCorrupt)?;
+
+        body.extend_from_slice(&::key_store::seal_slot_at(&c, &ent, 6)?[..]);
+    }
+
+    let mut header = [0u8; 280];
+
+    header[0..276].clone_from_slice(&raw[0..276]);
+    header[4..8].clone_from_slice(&6u32.to_le_bytes());
+    header[40..104].clone_from_slice(&*entries_hmac(&c, &body));
+
+    ::key_store::write_atomic(path, &[&header[..], &body[..]])
+}
+
+// v7 keeps each slot's name hash in the clear and binds it into the ad,
+// so every slot is resealed. the header is unchanged but for the version
+fn from_v6(pass: &::Password,
+           keyfile: Option<&Keyfile>,
+           path: &str)
+  -> ::Result<()>
+{
+    reseal(pass, keyfile, path, 6, 7)
+}
+
+// v8 moves an entry's path behind its state and gives it 1024 bytes,
+// so the slots grow from 552 to 1320 bytes
+fn from_v7(pass: &::Password,
+           keyfile: Option<&Keyfile>,
+           path: &str)
+  -> ::Result<()>
+{
+    reseal(pass, keyfile, path, 7, 8)
+}
+
+// opens every slot as sealed by version from and seals it as version to,
+// for the versions that share the v6 header
+fn reseal(pass: &::Password,
+          keyfile: Option<&Keyfile>,
+          path: &str,
+          from: u32,
+          to: u32)
+  -> ::Result<()>
+{
+    let raw = ::std::fs::read(path)?;
+    let hl  = ::key_store::HEADER_LEN as usize;
+    let sl  = ::key_store::slot_sizes(from).1;
+
+    if raw.len() < hl || !(raw.len() - hl).is_multiple_of(sl)
+    { return Err(Error::KeystoreTampered) }
+
+    let mut header = ::key_store::Header([0u8; 280]);
+    header.clone_from_slice(&raw[0..hl]);
+
+    match (header.keyfile_required(), keyfile.is_some()) {
+        (true, false) => return Err(Error::KeyfileRequired),
+        (false, true) => return Err(Error::UnexpectedKeyfile),
+        _             => (),
+    }
+
+    let kek = open_cipher(pass, keyfile, &raw)?;
+    let c   = KeyStore::unwrap(header.wrapped(), &kek)?;
+
+    if !::memcmp(header.hmac(), &*entries_hmac(&c, &raw[hl..]))
+    { return Err(Error::KeystoreTampered) }
+
+    let cnt = (raw.len() - hl) / sl;
+
+    let mut body = Vec::with_capacity(cnt * ::key_store::slot_sizes(to).1);
+    for s in raw[hl..].chunks(sl) {
+        let ent = ::key_store::open_slot_at(&c, s, from)
+            .ok_or(Error::EntryCorrupt)?;
+
+        body.extend_from_slice(&::key_store::seal_slot_at(&c, &ent, to)?[..]);
+    }
+
+    header[4..8].clone_from_slice(&to.to_le_bytes());
+    header[40..104].clone_from_slice(&*entries_hmac(&c, &body));
+
+    ::key_store::write_atomic(path, &[&header[..], &body[..]])
+}
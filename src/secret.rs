@@ -0,0 +1,177 @@
+/// passwords, and where they come from for runs nobody is there to
+/// type them: a file descriptor, a file, an environment variable or
+/// a command
+use std::io::prelude::*;
+use std::process::{Command, Stdio};
+use ::Error as Error;
+
+// longest secret a source may hand over, read into a buffer of
+// this size up front so it's never reallocated and copied around
+pub const MAX_SECRET: usize = 4096;
+
+// shortest password accepted, in bytes as it always has been, so
+// keystores made under that rule still open
+pub const MIN_PASSWORD: usize = 16;
+
+// where a passphrase comes from
+#[derive(Clone, PartialEq, Eq)]
+pub enum Source {
+    // an open descriptor, fd:N, read to the end through a duplicate so
+    // the caller's descriptor is left alone. fd:0 is stdin, stdout and
+    // stderr are refused
+    #[cfg(unix)]
+    Fd(i32),
+    // the first line of a file, file:PATH
+    File(String),
+    // an environment variable, env:NAME, removed once read so
+    // commands started afterwards don't inherit it
+    Env(String),
+    // the first line a shell command writes to stdout, cmd:COMMAND,
+    // as with `pass show name`
+    Command(String),
+}
+
+impl Source {
+    // kind:value, as given on the command line
+    pub fn parse(s: &str)
+      -> Option<Source>
+    {
+        let (kind, value) = match s.find(':') {
+            Some(x) => (&s[..x], &s[x + 1..]),
+            None    => return None,
+        };
+
+        if value.is_empty()
+        { return None }
+
+        match kind {
+            #[cfg(unix)]
+            "fd"   => value.parse().ok().map(Source::Fd),
+            "file" => Some(Source::File(String::from(value))),
+            "env"  => Some(Source::Env(String::from(value))),
+            "cmd"  => Some(Source::Command(String::from(value))),
+            _      => None,
+        }
+    }
+
+    pub fn read(&self)
+      -> ::Result<Password>
+    {
+        match *self {
+            #[cfg(unix)]
+            Source::Fd(fd) => {
+                use std::os::unix::io::FromRawFd;
+
+                if fd < 0 || fd == 1 || fd == 2
+                { return Err(invalid(&format!("can't read a password from fd {}", fd))) }
+
+                if unsafe { ::libc::fcntl(fd, ::libc::F_GETFD) } == -1
+                { return Err(invalid(&format!("fd {} isn't open", fd))) }
+
+                let dup = unsafe { ::libc::fcntl(fd, ::libc::F_DUPFD_CLOEXEC, 3) };
+
+                if dup == -1
+                { return Err(Error::Io(::std::io::Error::last_os_error())) }
+
+                // the duplicate is ours to close
+                let f = unsafe { ::std::fs::File::from_raw_fd(dup) };
+                Password::first_line(f)
+            },
+            Source::File(ref p)    => Password::first_line(::std::fs::File::open(p)?),
+            Source::Env(ref name)  => {
+                let v = ::std::env::var_os(name)
+                    .ok_or_else(|| invalid(&format!("{} isn't set", name)))?;
+                ::std::env::remove_var(name);
+
+                let v = v.into_string()
+                    .map_err(|_| invalid(&format!("{} isn't valid utf-8", name)))?;
+                Password::new(v)
+            },
+            Source::Command(ref cmd) => {
+                // stdin and stderr stay the terminal's, so the command
+                // can prompt for its own unlock
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd)
+                    .stdin(Stdio::inherit())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::inherit())
+                    .spawn()?;
+
+                let pass   = Password::first_line(child.stdout.take().unwrap());
+                let status = child.wait()?;
+
+                if !status.success()
+                { return Err(invalid(&format!("password command failed: {}", status))) }
+
+                pass
+            },
+        }
+    }
+}
+
+fn invalid(why: &str) -> Error {
+    Error::Io(::std::io::Error::new(::std::io::ErrorKind::InvalidData, why))
+}
+
+// a password of at least MIN_PASSWORD characters, zeroed when dropped.
+// not Debug or Display, so it can't end up in a log by accident
+pub struct Password(Vec<u8>);
+
+impl Password {
+    // takes over the string's buffer, nothing is copied
+    pub fn new(s: String)
+      -> ::Result<Password>
+    {
+        let p = Password(s.into_bytes());
+
+        if p.0.len() < MIN_PASSWORD
+        { return Err(Error::PasswordTooShort) }
+
+        Ok(p)
+    }
+
+    // the first line of src, without its line ending
+    fn first_line<R: Read>(src: R)
+      -> ::Result<Password>
+    {
+        let mut buf = Vec::with_capacity(MAX_SECRET + 1);
+        let read = src.take(MAX_SECRET as u64 + 1).read_to_end(&mut buf);
+
+        // zeroed from here on whatever happens
+        let mut p = Password(buf);
+        read?;
+
+        if p.0.len() > MAX_SECRET
+        { return Err(invalid("password source is too long")) }
+
+        let end = p.0.iter().position(|b| *b == b'\n').unwrap_or(p.0.len());
+        let end = if end > 0 && p.0[end - 1] == b'\r' { end - 1 } else { end };
+
+        ::memzero(&mut p.0[end..]);
+        p.0.truncate(end);
+
+        if ::std::str::from_utf8(&p.0).is_err()
+        { return Err(invalid("password isn't valid utf-8")) }
+
+        if p.0.len() < MIN_PASSWORD
+        { return Err(Error::PasswordTooShort) }
+
+        Ok(p)
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever built from valid utf-8
+        ::std::str::from_utf8(&self.0).unwrap_or("")
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Password {
+    fn drop(&mut self) {
+        ::memzero(&mut self.0);
+    }
+}
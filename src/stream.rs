@@ -0,0 +1,250 @@
+/// std::io adapters producing the same chunked xchacha20 stream and
+/// seal as Crypt, for pipes, sockets, empty files and anything else
+/// that can't be mapped
+use std::io;
+use std::io::prelude::*;
+use ::cipher::Cipher as Cipher;
+use ::tag::Seal as Seal;
+
+// encrypts one chunk in place and returns its mac
+fn seal(ciph: &Cipher,
+        ic: u64,
+        chunk: &mut [u8])
+  -> ::KTag
+{
+    ::xcc::stream_xor_ic_inplace(chunk,
+                                 &ciph.nons,
+                                 ic,
+                                 &ciph.keys);
+
+    ::tag::chunk_mac(ciph, chunk)
+}
+
+// macs one chunk of ciphertext and decrypts it in place
+fn open(ciph: &Cipher,
+        ic: u64,
+        chunk: &mut [u8])
+  -> ::KTag
+{
+    let mac = ::tag::chunk_mac(ciph, chunk);
+
+    ::xcc::stream_xor_ic_inplace(chunk,
+                                 &ciph.nons,
+                                 ic,
+                                 &ciph.keys);
+
+    mac
+}
+
+// Error isn't Clone, so a failed check is rebuilt for every later read
+fn refail(e: &::Error) -> ::Error {
+    match *e {
+        ::Error::Truncated => ::Error::Truncated,
+        ::Error::Extended  => ::Error::Extended,
+        ::Error::Reordered => ::Error::Reordered,
+        _                  => ::Error::FileTampered,
+    }
+}
+
+// plaintext buffer, zeroed when dropped
+struct Buf(Vec<u8>);
+
+impl Drop for Buf {
+    fn drop(&mut self) {
+        ::memzero(&mut self.0);
+    }
+}
+
+// plaintext and ciphertext are cut into chunk sized pieces, the last
+// one short, exactly as Crypt cuts up a mapped file
+
+// buffers plaintext a chunk at a time and writes its ciphertext to the
+// inner writer. finish must be called to flush the last chunk and get
+// the seal, dropping the writer loses both
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    ciph: Cipher,
+    macs: Vec<::KTag>,
+    len: u64,
+    buf: Buf,
+    chunk: usize,
+    idx: usize,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    // chunk must already have passed cipher::check_chunk
+    pub fn new(inner: W,
+               ciph: Cipher,
+               chunk: usize)
+      -> EncryptWriter<W>
+    {
+        EncryptWriter {
+            inner,
+            ciph,
+            macs: Vec::new(),
+            len: 0,
+            buf: Buf(Vec::with_capacity(chunk)),
+            chunk,
+            idx: 0,
+        }
+    }
+
+    fn flush_chunk(&mut self)
+      -> io::Result<()>
+    {
+        let mac = seal(&self.ciph, ::cipher::ic(self.idx, self.chunk), &mut self.buf.0);
+
+        self.inner.write_all(&self.buf.0)?;
+        self.macs.push(mac);
+        self.len += self.buf.0.len() as u64;
+        self.buf.0.clear();
+        self.idx += 1;
+
+        Ok(())
+    }
+
+    // writes the short last chunk and returns the inner writer with the
+    // file's seal, as stored in its keystore entry
+    pub fn finish(mut self)
+      -> io::Result<(W, Seal)>
+    {
+        if !self.buf.0.is_empty() {
+            self.flush_chunk()?;
+        }
+
+        self.inner.flush()?;
+
+        let seal = ::tag::seal(&self.ciph, &self.macs, self.len);
+
+        Ok((self.inner, seal))
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = ::std::cmp::min(self.chunk - self.buf.0.len(), data.len());
+        self.buf.0.extend_from_slice(&data[..n]);
+
+        if self.buf.0.len() == self.chunk {
+            self.flush_chunk()?;
+        }
+
+        Ok(n)
+    }
+
+    // only whole chunks can go out before finish
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// decrypts ciphertext from the inner reader a chunk at a time. the seal
+// can only be checked once the whole stream is read, so a read of 0
+// bytes is what vouches for everything before it: if the stream was
+// tampered with, that final read fails with InvalidData instead,
+// wrapping the Error that says how
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    ciph: Cipher,
+    macs: Vec<::KTag>,
+    len: u64,
+    seal: Seal,
+    done: bool,
+    tampered: Option<::Error>,
+    buf: Buf,
+    pos: usize,
+    chunk: usize,
+    idx: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub fn new(inner: R,
+               ciph: Cipher,
+               seal: Seal,
+               chunk: usize)
+      -> ::Result<DecryptReader<R>>
+    {
+        ::cipher::check_chunk(chunk)?;
+
+        Ok(
+        DecryptReader {
+            inner,
+            ciph,
+            macs: Vec::new(),
+            len: 0,
+            seal,
+            done: false,
+            tampered: None,
+            buf: Buf(Vec::with_capacity(chunk)),
+            pos: 0,
+            chunk,
+            idx: 0,
+        })
+    }
+
+    pub fn into_inner(self)
+      -> R
+    { self.inner }
+
+    // reads the next whole chunk, short only at the end of the stream
+    fn fill_chunk(&mut self)
+      -> io::Result<()>
+    {
+        ::memzero(&mut self.buf.0);
+        self.buf.0.resize(self.chunk, 0);
+        self.pos = 0;
+
+        let mut got = 0;
+        while got < self.chunk {
+            match self.inner.read(&mut self.buf.0[got..]) {
+                Ok(0)  => break,
+                Ok(n)  => got += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.buf.0.truncate(got);
+
+        if got == 0 {
+            if !self.done {
+                self.done     = true;
+                self.tampered = ::tag::check(&self.ciph, &self.macs, self.len, &self.seal).err();
+            }
+        } else if self.done {
+            // data after the end of the stream was already vouched for
+            if self.tampered.is_none() {
+                self.tampered = Some(::Error::Extended);
+            }
+        } else {
+            let mac = open(&self.ciph, ::cipher::ic(self.idx, self.chunk), &mut self.buf.0);
+
+            self.macs.push(mac);
+            self.len += got as u64;
+            self.idx += 1;
+        }
+
+        // keeps failing rather than passing a later read off as a clean end
+        if let Some(ref e) = self.tampered {
+            self.buf.0.clear();
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      refail(e)))
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.0.len() {
+            self.fill_chunk()?;
+        }
+
+        let n = ::std::cmp::min(out.len(), self.buf.0.len() - self.pos);
+        out[..n].clone_from_slice(&self.buf.0[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
@@ -0,0 +1,286 @@
+/// file tags: keyed blake2b per chunk folded into a keccak final tag,
+/// bound to each chunk's position and to the file's exact length
+use blake2_rfc::blake2b::Blake2b as Blake2b;
+use std::ops::Range;
+use ::cipher::Cipher as Cipher;
+use ::Error as Error;
+
+pub const MAGIC: &[u8; 4] = b"SMTG";
+
+// magic 4 | count 8, followed by count 64 byte chunk macs
+const SIDECAR_LEN: usize = 12;
+
+// what an entry records about its file's ciphertext. legacy seals
+// predate the binding and only carry the bare final tag
+#[derive(Clone)]
+pub struct Seal {
+    pub tag: [u8; 64],
+    pub order: [u8; 16],
+    pub len: u64,
+    pub bound: bool,
+}
+
+impl Seal {
+    // placeholder while a file has keys but no ciphertext yet
+    pub fn empty() -> Seal {
+        Seal {
+            tag: [0u8; 64],
+            order: [0u8; 16],
+            len: 0,
+            bound: true,
+        }
+    }
+}
+
+// keyed blake2b of one chunk on its own, the same wherever it sits.
+// this alone was the chunk tag before tags were bound
+pub fn chunk_mac(ciph: &Cipher,
+                 data: &[u8])
+  -> ::KTag
+{
+    let mut h = Blake2b::with_key(64, &ciph.auth[..]);
+    h.update(data);
+
+    let mut r = ::KTag([0u8; 64]);
+    r.clone_from_slice(h.finalize().as_bytes());
+    r
+}
+
+// macs for the chunks of a file in order, and its exact length
+pub fn seal(ciph: &Cipher,
+            macs: &[::KTag],
+            len: u64)
+  -> Seal
+{
+    let mut finaler = ::Keccak::new_keccak512();
+    finaler.update(&ciph.afin[..]);
+
+    for (idx, m) in macs.iter().enumerate() {
+        let mut h = Blake2b::with_key(64, &ciph.auth[..]);
+        h.update(&(idx as u64).to_le_bytes());
+        h.update(&m[..]);
+
+        finaler.update(h.finalize().as_bytes());
+    }
+
+    // count and length go last so a stream can tag as it goes
+    finaler.update(&(macs.len() as u64).to_le_bytes());
+    finaler.update(&len.to_le_bytes());
+
+    let mut s = Seal::empty();
+    finaler.finalize(&mut s.tag);
+
+    s.order = order(ciph, macs);
+    s.len   = len;
+
+    s
+}
+
+// the pre-binding final tag: keccak over afin and the bare chunk macs
+pub fn legacy(ciph: &Cipher,
+              macs: &[::KTag])
+  -> Seal
+{
+    let mut finaler = ::Keccak::new_keccak512();
+    finaler.update(&ciph.afin[..]);
+
+    for m in macs {
+        finaler.update(&m[..]);
+    }
+
+    let mut s = Seal::empty();
+    finaler.finalize(&mut s.tag);
+    s.bound = false;
+
+    s
+}
+
+// digest of the chunk macs sorted, so it survives chunks being moved
+// around. only used to tell reordering apart from other damage once
+// the real tag has already failed
+fn order(ciph: &Cipher,
+         macs: &[::KTag])
+  -> [u8; 16]
+{
+    let mut sorted: Vec<[u8; 64]> = macs.iter().map(|m| m.0).collect();
+    sorted.sort_unstable();
+
+    let mut finaler = ::Keccak::new_keccak512();
+    finaler.update(&ciph.afin[..]);
+    finaler.update(b"order");
+
+    for m in &sorted {
+        finaler.update(&m[..]);
+    }
+
+    let mut full = [0u8; 64];
+    finaler.finalize(&mut full);
+
+    let mut r = [0u8; 16];
+    r.clone_from_slice(&full[0..16]);
+    r
+}
+
+// checks a file's chunk macs and length against what its entry expects
+pub fn check(ciph: &Cipher,
+             macs: &[::KTag],
+             len: u64,
+             expect: &Seal)
+  -> ::Result<()>
+{
+    if !expect.bound {
+        if ::memcmp(&legacy(ciph, macs).tag, &expect.tag)
+        { return Ok(()) }
+
+        return Err(Error::FileTampered)
+    }
+
+    if len < expect.len
+    { return Err(Error::Truncated) }
+    if len > expect.len
+    { return Err(Error::Extended) }
+
+    let found = seal(ciph, macs, len);
+
+    if ::memcmp(&found.tag, &expect.tag)
+    { return Ok(()) }
+    if ::memcmp(&found.order, &expect.order)
+    { return Err(Error::Reordered) }
+
+    Err(Error::FileTampered)
+}
+
+// what authenticate found. damaged holds runs of chunk indices whose
+// ciphertext doesn't match the sidecar, or the whole file when there's
+// no sidecar to go by. chunks past the end of a truncated file count as
+// damaged, as do any an extended file grew. len is the length the file
+// was sealed with, or its length now for legacy entries
+pub struct Report {
+    pub chunk: usize,
+    pub len: u64,
+    pub fault: Option<Error>,
+    pub damaged: Vec<Range<usize>>,
+}
+
+impl Report {
+    pub fn intact(&self) -> bool {
+        self.fault.is_none()
+    }
+
+    // damaged runs as byte ranges of the file as it was sealed, which is
+    // what has to come back from a backup
+    pub fn byte_ranges(&self) -> Vec<Range<u64>> {
+        self.damaged.iter()
+            .map(|r| (r.start * self.chunk) as u64..(r.end * self.chunk) as u64)
+            .map(|r| r.start.min(self.len)..r.end.min(self.len))
+            .filter(|r| r.start < r.end)
+            .collect()
+    }
+
+    pub fn into_result(self) -> ::Result<()> {
+        match self.fault {
+            Some(e) => Err(e),
+            None    => Ok(()),
+        }
+    }
+}
+
+// runs of chunks whose macs differ from the stored ones, over expect
+// chunks or however many the file has now if that's more
+pub fn locate(found: &[::KTag],
+              stored: Option<&[::KTag]>,
+              expect: usize)
+  -> Vec<Range<usize>>
+{
+    let n = ::std::cmp::max(found.len(), expect);
+    let mut runs: Vec<Range<usize>> = Vec::new();
+
+    for idx in 0..n {
+        let bad = match stored {
+            Some(s) => idx >= found.len() || idx >= s.len() || !::memcmp(&found[idx][..], &s[idx][..]),
+            None    => true,
+        };
+
+        if !bad
+        { continue }
+
+        match runs.last_mut() {
+            Some(r) if r.end == idx => r.end += 1,
+            _                       => runs.push(idx..idx + 1),
+        }
+    }
+
+    runs
+}
+
+// chunk macs are kept in a sidecar so single chunks can be checked
+// without reading the whole file. the sidecar isn't trusted on its own,
+// only once its macs reproduce the entry's seal
+pub fn sidecar_for(target: &str) -> String {
+    String::from(target) + ".tags"
+}
+
+pub fn store(target: &str,
+             macs: &[::KTag])
+  -> ::Result<()>
+{
+    let mut h = [0u8; SIDECAR_LEN];
+
+    h[0..4].clone_from_slice(MAGIC);
+    h[4..12].clone_from_slice(&(macs.len() as u64).to_le_bytes());
+
+    let mut parts: Vec<&[u8]> = vec![&h[..]];
+    parts.extend(macs.iter().map(|m| &m[..]));
+
+    ::key_store::write_atomic(&sidecar_for(target), &parts)
+}
+
+// the sidecar's macs if it vouches for the file under expect, None
+// when it's missing, damaged or left over from other keys
+pub fn stored(target: &str,
+              ciph: &Cipher,
+              expect: &Seal)
+  -> ::Result<Option<Vec<::KTag>>>
+{
+    let raw = match ::std::fs::read(sidecar_for(target)) {
+        Ok(r)  => r,
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound
+               => return Ok(None),
+        Err(e) => return Err(Error::Io(e)),
+    };
+
+    if raw.len() < SIDECAR_LEN || &raw[0..4] != MAGIC
+    { return Ok(None) }
+
+    let mut w = [0u8; 8];
+    w.clone_from_slice(&raw[4..12]);
+    let count = u64::from_le_bytes(w);
+
+    if count.checked_mul(64) != Some((raw.len() - SIDECAR_LEN) as u64)
+    { return Ok(None) }
+
+    let macs: Vec<::KTag> = raw[SIDECAR_LEN..]
+        .chunks(64)
+        .filter_map(::KTag::from_slice)
+        .collect();
+
+    // a bound seal fixes the length, a legacy one can't say so check
+    // the macs against it with whatever length it was sealed under
+    let found = if expect.bound { seal(ciph, &macs, expect.len) } else { legacy(ciph, &macs) };
+
+    if !::memcmp(&found.tag, &expect.tag)
+    { return Ok(None) }
+
+    Ok(Some(macs))
+}
+
+pub fn discard(target: &str)
+  -> ::Result<()>
+{
+    match ::std::fs::remove_file(sidecar_for(target)) {
+        Ok(())  => Ok(()),
+        Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound
+                => Ok(()),
+        Err(e)  => Err(Error::Io(e)),
+    }
+}